@@ -0,0 +1,157 @@
+use {
+    proc_macro::TokenStream,
+    quote::quote,
+    syn::{parse_macro_input, Data, DataStruct, DeriveInput, Fields, Ident, LitStr, Path},
+};
+
+/// Derives `gossip_glomers::protocol::Body` for a message type.
+///
+/// The wire type defaults to the snake case name of the type, and can be set
+/// with `#[message(type = "read_ok")]`. Adding `reply_to = Read` also
+/// implements `Rpc` for `Read`, with this type as its response.
+#[proc_macro_derive(MaelstromMessage, attributes(message))]
+pub fn derive_maelstrom_message(input: TokenStream) -> TokenStream {
+    let input = parse_macro_input!(input as DeriveInput);
+
+    match expand(input) {
+        Ok(tokens) => tokens.into(),
+        Err(error) => error.to_compile_error().into(),
+    }
+}
+
+fn expand(input: DeriveInput) -> syn::Result<proc_macro2::TokenStream> {
+    // Bodies have to serialize to a JSON object, so that `type`, `msg_id`
+    // and `in_reply_to` can be added to them. Unit structs serialize to
+    // `null`, so `struct Read {}` is needed rather than `struct Read;`.
+    match &input.data {
+        Data::Struct(DataStruct {
+            fields: Fields::Named(_),
+            ..
+        }) => {}
+        _ => {
+            return Err(syn::Error::new_spanned(
+                &input.ident,
+                "MaelstromMessage can only be derived for structs with named fields, \
+                 such as `struct Read {}`",
+            ))
+        }
+    }
+
+    let mut msg_type = None;
+    let mut reply_to = None;
+
+    for attr in input
+        .attrs
+        .iter()
+        .filter(|attr| attr.path().is_ident("message"))
+    {
+        attr.parse_nested_meta(|meta| {
+            if meta.path.is_ident("type") {
+                msg_type = Some(meta.value()?.parse::<LitStr>()?.value());
+                Ok(())
+            } else if meta.path.is_ident("reply_to") {
+                reply_to = Some(meta.value()?.parse::<Path>()?);
+                Ok(())
+            } else {
+                Err(meta.error("expected `type` or `reply_to`"))
+            }
+        })?;
+    }
+
+    let name = &input.ident;
+    let msg_type = msg_type.unwrap_or_else(|| snake_case(name));
+    let (impl_generics, ty_generics, where_clause) = input.generics.split_for_impl();
+
+    let rpc = reply_to.map(|request| {
+        quote! {
+            impl ::gossip_glomers::protocol::Rpc for #request {
+                type Response = #name #ty_generics;
+            }
+        }
+    });
+
+    Ok(quote! {
+        impl #impl_generics ::gossip_glomers::protocol::Body for #name #ty_generics #where_clause {
+            const TYPE: &'static str = #msg_type;
+        }
+
+        #rpc
+    })
+}
+
+fn snake_case(ident: &Ident) -> String {
+    let mut snake_case = String::new();
+
+    for (i, ch) in ident.to_string().chars().enumerate() {
+        if ch.is_uppercase() {
+            if i > 0 {
+                snake_case.push('_');
+            }
+            snake_case.extend(ch.to_lowercase());
+        } else {
+            snake_case.push(ch);
+        }
+    }
+
+    snake_case
+}
+
+#[cfg(test)]
+mod tests {
+    use {super::*, syn::parse_quote};
+
+    fn expand_to_string(input: DeriveInput) -> String {
+        expand(input).unwrap().to_string()
+    }
+
+    #[test]
+    fn the_type_defaults_to_the_snake_case_name() {
+        let tokens = expand_to_string(parse_quote! {
+            struct ReadOk { messages: Vec<u64> }
+        });
+        assert!(tokens.contains(r#"const TYPE : & 'static str = "read_ok""#));
+        assert!(!tokens.contains("Rpc"));
+    }
+
+    #[test]
+    fn the_type_can_be_set() {
+        let tokens = expand_to_string(parse_quote! {
+            #[message(type = "txn")]
+            struct Transaction {}
+        });
+        assert!(tokens.contains(r#"const TYPE : & 'static str = "txn""#));
+    }
+
+    #[test]
+    fn reply_to_implements_rpc_for_the_request() {
+        let tokens = expand_to_string(parse_quote! {
+            #[message(reply_to = Read)]
+            struct ReadOk {}
+        });
+        assert!(tokens.contains("impl :: gossip_glomers :: protocol :: Rpc for Read"));
+        assert!(tokens.contains("type Response = ReadOk"));
+    }
+
+    #[test]
+    fn unknown_attributes_are_rejected() {
+        assert!(expand(parse_quote! {
+            #[message(kind = "read")]
+            struct Read {}
+        })
+        .is_err());
+    }
+
+    #[test]
+    fn only_structs_with_named_fields_are_accepted() {
+        let rejected: [DeriveInput; 4] = [
+            parse_quote! { struct Counter(u64); },
+            parse_quote! { struct Read; },
+            parse_quote! { enum Request { Read, Write } },
+            parse_quote! { union Bits { a: u32, b: f32 } },
+        ];
+
+        for input in rejected {
+            assert!(expand(input).is_err());
+        }
+    }
+}
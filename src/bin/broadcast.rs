@@ -1,46 +1,65 @@
 use {
+    futures::future::join_all,
     gossip_glomers::{
         error::Error,
-        node::{Node, NodeContext},
-        protocol::{
-            Broadcast, BroadcastOk, MessageBody, NodeId, Read, ReadOk, Topology, TopologyOk,
-        },
+        extract::{FromRef, Json, State},
+        node::{Node, NodeContext, Router},
+        protocol::{Broadcast, BroadcastOk, NodeId, Read, ReadOk, Topology, TopologyOk},
         server::Server,
+        transport::TcpTransport,
     },
     serde_json::Value,
     std::{
-        collections::HashSet,
+        collections::{HashMap, HashSet},
         sync::{Arc, Mutex},
         time::Duration,
     },
-    tokio::time::sleep,
-    tower::ServiceExt,
 };
 
-#[derive(Default)]
-struct State {
-    messages: HashSet<i64>,
+const TIME_BETWEEN_GOSSIPING: Duration = Duration::from_millis(250);
+
+/// Every message this node has seen.
+#[derive(Clone, Default)]
+struct MessageStore(Arc<Mutex<HashSet<i64>>>);
+
+/// This node's neighbours, and the messages each of them is known to have.
+#[derive(Clone, Default)]
+struct Neighbours(Arc<Mutex<HashMap<NodeId, HashSet<i64>>>>);
+
+#[derive(Clone, Default)]
+struct NodeState {
+    messages: MessageStore,
+    neighbours: Neighbours,
 }
 
-type SharedState = Arc<Mutex<State>>;
+impl FromRef<NodeState> for MessageStore {
+    fn from_ref(state: &NodeState) -> Self {
+        state.messages.clone()
+    }
+}
+
+impl FromRef<NodeState> for Neighbours {
+    fn from_ref(state: &NodeState) -> Self {
+        state.neighbours.clone()
+    }
+}
 
 async fn broadcast(
-    _: NodeContext,
-    state: SharedState,
-    Broadcast { message }: Broadcast,
+    State(MessageStore(messages)): State<MessageStore>,
+    Json(Broadcast { message }): Json<Broadcast>,
 ) -> Result<BroadcastOk, Error> {
-    let mut state = state.lock().unwrap();
+    let mut messages = messages.lock().unwrap();
 
     match message {
         Value::Number(number) => {
             if let Some(number) = number.as_i64() {
-                state.messages.insert(number);
+                messages.insert(number);
             }
         }
         Value::Array(array) => {
             for number in array {
                 if let Some(number) = number.as_i64() {
-                    state.messages.insert(number);
+                    messages.insert(number);
                 }
             }
         }
@@ -50,88 +69,111 @@ async fn broadcast(
     Ok(BroadcastOk {})
 }
 
-async fn read(_: NodeContext, state: SharedState, _: Read) -> Result<ReadOk, Error> {
+async fn read(State(MessageStore(messages)): State<MessageStore>) -> Result<ReadOk, Error> {
     Ok(ReadOk {
-        messages: state.lock().unwrap().messages.iter().copied().collect(),
+        messages: messages.lock().unwrap().iter().copied().collect(),
     })
 }
 
 async fn topology(
     context: NodeContext,
-    state: SharedState,
-    Topology { mut topology }: Topology,
+    State(Neighbours(neighbours)): State<Neighbours>,
+    Json(Topology { mut topology }): Json<Topology>,
 ) -> Result<TopologyOk, Error> {
-    if let Some(neighbours) = topology.remove(&context.node_id()) {
-        for neighbour in neighbours {
-            tokio::spawn(gossip_with_neighbour(
-                neighbour,
-                Arc::clone(&state),
-                context.clone(),
-            ));
-        }
+    if let Some(new_neighbours) = topology.remove(&context.node_id()) {
+        let mut neighbours = neighbours.lock().unwrap();
+        let mut messages_neighbours_know = std::mem::take(&mut *neighbours);
+
+        *neighbours = new_neighbours
+            .into_iter()
+            .map(|neighbour| {
+                let known = messages_neighbours_know
+                    .remove(&neighbour)
+                    .unwrap_or_default();
+                (neighbour, known)
+            })
+            .collect();
     }
     Ok(TopologyOk {})
 }
 
-async fn gossip_with_neighbour(neighbour: NodeId, state: SharedState, context: NodeContext) {
-    let mut messages_neighbour_knows = HashSet::<i64>::default();
+async fn gossip(context: NodeContext, state: NodeState) {
+    let neighbours: Vec<NodeId> = state.neighbours.0.lock().unwrap().keys().cloned().collect();
 
-    loop {
-        const TIME_BETWEEN_GOSSIPING: Duration = Duration::from_millis(250);
-        sleep(TIME_BETWEEN_GOSSIPING).await;
+    join_all(
+        neighbours
+            .into_iter()
+            .map(|neighbour| gossip_with_neighbour(neighbour, &state, &context)),
+    )
+    .await;
+}
 
-        let response = context
-            .send_to(neighbour.clone())
-            .oneshot(Read {}.into())
-            .await
-            .map(|message| message.body)
-            .map(serde_json::from_value);
-
-        if let Ok(Ok(MessageBody::ReadOk(ReadOk {
-            messages: Value::Array(messages),
-        }))) = response
-        {
-            for message in messages.iter().filter_map(|value| value.as_i64()) {
-                messages_neighbour_knows.insert(message);
-            }
-        }
+async fn gossip_with_neighbour(neighbour: NodeId, state: &NodeState, context: &NodeContext) {
+    if let Ok(ReadOk {
+        messages: Value::Array(messages),
+    }) = context.call(neighbour.clone(), Read {}).await
+    {
+        learn(
+            &state.neighbours,
+            &neighbour,
+            messages.iter().filter_map(Value::as_i64),
+        );
+    }
 
-        let messages_to_tell_neighbour_about = {
-            state
-                .lock()
-                .unwrap()
-                .messages
-                .difference(&messages_neighbour_knows)
-                .copied()
-                .collect::<Vec<_>>()
+    let messages_to_tell_neighbour_about = {
+        let known = match state.neighbours.0.lock().unwrap().get(&neighbour) {
+            Some(known) => known.clone(),
+            None => return,
         };
+        let messages = state.messages.0.lock().unwrap();
+        messages.difference(&known).copied().collect::<Vec<_>>()
+    };
 
-        if !messages_to_tell_neighbour_about.is_empty() {
-            let response = context
-                .send_to(neighbour.clone())
-                .oneshot(
-                    Broadcast {
-                        message: messages_to_tell_neighbour_about.clone().into(),
-                    }
-                    .into(),
-                )
-                .await
-                .map(|message| message.body)
-                .map(serde_json::from_value);
-
-            if let Ok(Ok(MessageBody::BroadcastOk(BroadcastOk {}))) = response {
-                messages_neighbour_knows.extend(messages_to_tell_neighbour_about);
-            }
+    if !messages_to_tell_neighbour_about.is_empty() {
+        let response = context
+            .call(
+                neighbour.clone(),
+                Broadcast {
+                    message: messages_to_tell_neighbour_about.clone().into(),
+                },
+            )
+            .await;
+
+        if let Ok(BroadcastOk {}) = response {
+            learn(
+                &state.neighbours,
+                &neighbour,
+                messages_to_tell_neighbour_about,
+            );
         }
     }
 }
 
+fn learn(neighbours: &Neighbours, neighbour: &NodeId, messages: impl IntoIterator<Item = i64>) {
+    if let Some(known) = neighbours.0.lock().unwrap().get_mut(neighbour) {
+        known.extend(messages);
+    }
+}
+
+fn messages() -> Router<MessageStore> {
+    Router::new()
+        .route("broadcast", broadcast)
+        .route("read", read)
+}
+
+fn neighbours() -> Router<Neighbours> {
+    Router::new().route("topology", topology)
+}
+
 #[tokio::main(flavor = "current_thread")]
 async fn main() -> Result<(), Error> {
-    let node = Node::with_state(SharedState::default())
-        .add_handler("broadcast", broadcast)
-        .add_handler("read", read)
-        .add_handler("topology", topology);
-
-    Server::default().serve(node).await
+    let node = Node::with_state(NodeState::default())
+        .nest(messages())?
+        .nest(neighbours())?
+        .add_periodic_task("gossip", TIME_BETWEEN_GOSSIPING, gossip);
+
+    match TcpTransport::from_env().await? {
+        Some(transport) => Server::new(transport).serve(node).await,
+        None => Server::default().serve(node).await,
+    }
 }
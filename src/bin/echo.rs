@@ -1,11 +1,13 @@
 use gossip_glomers::{
     error::Error,
-    node::{Node, NodeContext},
+    extract::Json,
+    node::Node,
     protocol::{Echo, EchoOk},
     server::Server,
+    transport::TcpTransport,
 };
 
-async fn echo(_: NodeContext, _: (), Echo { echo }: Echo) -> Result<EchoOk, Error> {
+async fn echo(Json(Echo { echo }): Json<Echo>) -> Result<EchoOk, Error> {
     Ok(EchoOk { echo })
 }
 
@@ -13,5 +15,8 @@ async fn echo(_: NodeContext, _: (), Echo { echo }: Echo) -> Result<EchoOk, Erro
 async fn main() -> Result<(), Error> {
     let node = Node::default().add_handler("echo", echo);
 
-    Server::default().serve(node).await
+    match TcpTransport::from_env().await? {
+        Some(transport) => Server::new(transport).serve(node).await,
+        None => Server::default().serve(node).await,
+    }
 }
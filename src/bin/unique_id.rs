@@ -1,14 +1,11 @@
 use {
     gossip_glomers::{
-        error::Error,
-        node::{Node, NodeContext},
-        protocol::{Generate, GenerateOk},
-        server::Server,
+        error::Error, node::Node, protocol::GenerateOk, server::Server, transport::TcpTransport,
     },
     uuid::Uuid,
 };
 
-async fn generate(_: NodeContext, _: (), _: Generate) -> Result<GenerateOk, Error> {
+async fn generate() -> Result<GenerateOk, Error> {
     Ok(GenerateOk { id: Uuid::new_v4() })
 }
 
@@ -16,5 +13,8 @@ async fn generate(_: NodeContext, _: (), _: Generate) -> Result<GenerateOk, Erro
 async fn main() -> Result<(), Error> {
     let node = Node::default().add_handler("generate", generate);
 
-    Server::default().serve(node).await
+    match TcpTransport::from_env().await? {
+        Some(transport) => Server::new(transport).serve(node).await,
+        None => Server::default().serve(node).await,
+    }
 }
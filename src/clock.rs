@@ -0,0 +1,219 @@
+use {
+    futures::future::{self, BoxFuture, Either, FutureExt},
+    std::{
+        collections::{btree_map::Entry, BTreeMap},
+        fmt::Debug,
+        future::Future,
+        pin::{pin, Pin},
+        sync::{Arc, Mutex},
+        task::{Context, Poll, Waker},
+        time::Duration,
+    },
+    tokio::time::Instant,
+};
+
+/// The source of time behind a `Clock`.
+pub trait TimeSource: Debug + Send + Sync + 'static {
+    fn now(&self) -> Instant;
+
+    fn sleep_until(&self, deadline: Instant) -> BoxFuture<'static, ()>;
+}
+
+/// The timers available to a node. Handlers should use this rather than
+/// `tokio::time` directly, so that tests can control how time passes.
+#[derive(Debug, Clone)]
+pub struct Clock(Arc<dyn TimeSource>);
+
+#[derive(Debug, Copy, Clone, Eq, PartialEq, thiserror::Error)]
+#[error("deadline elapsed")]
+pub struct Elapsed;
+
+impl Clock {
+    pub fn new(source: impl TimeSource) -> Self {
+        Self(Arc::new(source))
+    }
+
+    /// Real time, via `tokio::time`. When the runtime's clock is paused (as it
+    /// is in the simulator) this is virtual time instead.
+    pub fn system() -> Self {
+        Self::new(SystemTime)
+    }
+
+    pub fn now(&self) -> Instant {
+        self.0.now()
+    }
+
+    pub async fn sleep(&self, duration: Duration) {
+        self.sleep_until(self.now() + duration).await
+    }
+
+    pub async fn sleep_until(&self, deadline: Instant) {
+        self.0.sleep_until(deadline).await
+    }
+
+    /// Ticks every `period`, starting immediately. Missed ticks are skipped
+    /// rather than delivered in a burst.
+    pub fn interval(&self, period: Duration) -> Interval {
+        Interval {
+            clock: self.clone(),
+            next: self.now(),
+            period,
+        }
+    }
+
+    pub async fn timeout<F>(&self, duration: Duration, future: F) -> Result<F::Output, Elapsed>
+    where
+        F: Future,
+    {
+        let deadline = pin!(self.sleep(duration));
+        match future::select(pin!(future), deadline).await {
+            Either::Left((output, _)) => Ok(output),
+            Either::Right(_) => Err(Elapsed),
+        }
+    }
+}
+
+impl Default for Clock {
+    fn default() -> Self {
+        Self::system()
+    }
+}
+
+#[derive(Debug)]
+pub struct Interval {
+    clock: Clock,
+    next: Instant,
+    period: Duration,
+}
+
+impl Interval {
+    pub async fn tick(&mut self) -> Instant {
+        self.clock.sleep_until(self.next).await;
+
+        let tick = self.next;
+        self.next = (tick + self.period).max(self.clock.now());
+        tick
+    }
+}
+
+#[derive(Debug)]
+struct SystemTime;
+
+impl TimeSource for SystemTime {
+    fn now(&self) -> Instant {
+        Instant::now()
+    }
+
+    fn sleep_until(&self, deadline: Instant) -> BoxFuture<'static, ()> {
+        tokio::time::sleep_until(deadline).boxed()
+    }
+}
+
+/// A clock that only moves when it is advanced, for tests that need exact
+/// control over timers.
+#[derive(Debug, Clone)]
+pub struct ManualClock(Arc<Mutex<ManualTime>>);
+
+#[derive(Debug)]
+struct ManualTime {
+    now: Instant,
+    /// The waker of each pending sleep, keyed by its deadline and then an ID,
+    /// so that a sleep polled again replaces its waker rather than adding one.
+    sleepers: BTreeMap<(Instant, u64), Waker>,
+    next_sleeper: u64,
+}
+
+impl ManualClock {
+    pub fn new() -> Self {
+        Self(Arc::new(Mutex::new(ManualTime {
+            now: Instant::now(),
+            sleepers: BTreeMap::new(),
+            next_sleeper: 0,
+        })))
+    }
+
+    pub fn clock(&self) -> Clock {
+        Clock::new(self.clone())
+    }
+
+    /// Moves time forward, waking any sleeps that are now due.
+    pub fn advance(&self, duration: Duration) {
+        let mut due = Vec::new();
+        {
+            let mut time = self.0.lock().unwrap();
+            time.now += duration;
+            let now = time.now;
+            while let Some(sleeper) = time.sleepers.first_entry() {
+                if sleeper.key().0 > now {
+                    break;
+                }
+                due.push(sleeper.remove());
+            }
+        }
+
+        for sleeper in due {
+            sleeper.wake();
+        }
+    }
+}
+
+impl Default for ManualClock {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl TimeSource for ManualClock {
+    fn now(&self) -> Instant {
+        self.0.lock().unwrap().now
+    }
+
+    fn sleep_until(&self, deadline: Instant) -> BoxFuture<'static, ()> {
+        let mut time = self.0.lock().unwrap();
+        let id = time.next_sleeper;
+        time.next_sleeper += 1;
+
+        ManualSleep {
+            time: Arc::clone(&self.0),
+            key: (deadline, id),
+        }
+        .boxed()
+    }
+}
+
+struct ManualSleep {
+    time: Arc<Mutex<ManualTime>>,
+    key: (Instant, u64),
+}
+
+impl Future for ManualSleep {
+    type Output = ();
+
+    fn poll(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<()> {
+        let mut time = self.time.lock().unwrap();
+        if time.now >= self.key.0 {
+            time.sleepers.remove(&self.key);
+            return Poll::Ready(());
+        }
+
+        match time.sleepers.entry(self.key) {
+            Entry::Occupied(mut sleeper) => {
+                if !sleeper.get().will_wake(cx.waker()) {
+                    sleeper.insert(cx.waker().clone());
+                }
+            }
+            Entry::Vacant(sleeper) => {
+                sleeper.insert(cx.waker().clone());
+            }
+        }
+        Poll::Pending
+    }
+}
+
+impl Drop for ManualSleep {
+    fn drop(&mut self) {
+        if let Ok(mut time) = self.time.lock() {
+            time.sleepers.remove(&self.key);
+        }
+    }
+}
@@ -1,3 +1,8 @@
+use {
+    serde::{Deserialize, Serialize},
+    std::fmt,
+};
+
 #[derive(thiserror::Error, Debug)]
 pub enum Error {
     #[error("not implemented")]
@@ -6,9 +11,181 @@ pub enum Error {
     #[error("request cancelled")]
     RequestCancelled,
 
+    #[error("request timed out")]
+    Timeout,
+
+    #[error("unexpected response: {0}")]
+    UnexpectedResponse(String),
+
+    #[error("input closed before init")]
+    ClosedBeforeInit,
+
+    #[error("transport closed")]
+    TransportClosed,
+
+    #[error("quorum not reached: {replies} of {quorum} replies")]
+    QuorumNotReached {
+        replies: usize,
+        quorum: usize,
+        /// The codes of the requests that failed.
+        failures: Vec<ErrorCode>,
+        /// How many requests were abandoned before they had an outcome.
+        pending: usize,
+    },
+
+    #[error("message body is not a JSON object")]
+    BodyNotAnObject,
+
+    #[error("{0} is routed more than once")]
+    DuplicateRoute(String),
+
+    #[error("{0} has a route layer but no route")]
+    MissingRoute(String),
+
+    #[error("{code}: {text}")]
+    Maelstrom { code: ErrorCode, text: String },
+
     #[error(transparent)]
     IoError(#[from] std::io::Error),
 
     #[error(transparent)]
     JsonError(#[from] serde_json::Error),
 }
+
+impl Error {
+    pub fn new(code: ErrorCode, text: impl Into<String>) -> Self {
+        Self::Maelstrom {
+            code,
+            text: text.into(),
+        }
+    }
+
+    pub fn code(&self) -> ErrorCode {
+        match self {
+            Self::NotImplemented => ErrorCode::NotSupported,
+            Self::RequestCancelled => ErrorCode::Timeout,
+            Self::Timeout => ErrorCode::Timeout,
+            Self::UnexpectedResponse(_) => ErrorCode::Crash,
+            Self::ClosedBeforeInit => ErrorCode::Crash,
+            Self::TransportClosed => ErrorCode::Crash,
+            Self::QuorumNotReached {
+                replies,
+                failures,
+                pending,
+                ..
+            } => quorum_code(*replies, failures, *pending),
+            Self::DuplicateRoute(_) => ErrorCode::Crash,
+            Self::MissingRoute(_) => ErrorCode::Crash,
+            Self::BodyNotAnObject => ErrorCode::Crash,
+            Self::Maelstrom { code, .. } => *code,
+            Self::IoError(_) => ErrorCode::Crash,
+            // Requests that don't parse are rejected by the `Json` extractor,
+            // so this is something parsed later, after the request may have
+            // taken effect.
+            Self::JsonError(_) => ErrorCode::Crash,
+        }
+    }
+}
+
+/// A quorum call only failed definitely if every request it made did, as a
+/// request that succeeded or is still pending may have taken effect. Then its
+/// code is theirs if they agree, or `TemporarilyUnavailable` if not.
+fn quorum_code(replies: usize, failures: &[ErrorCode], pending: usize) -> ErrorCode {
+    let definite = replies == 0 && pending == 0 && failures.iter().all(|code| code.is_definite());
+    match failures {
+        [first, rest @ ..] if definite => {
+            if rest.iter().all(|code| code == first) {
+                *first
+            } else {
+                ErrorCode::TemporarilyUnavailable
+            }
+        }
+        _ => ErrorCode::Timeout,
+    }
+}
+
+/// The error codes defined by the Maelstrom protocol, see
+/// <https://github.com/jepsen-io/maelstrom/blob/main/doc/protocol.md#errors>.
+#[derive(Debug, Copy, Clone, Eq, PartialEq, Hash, Serialize, Deserialize)]
+#[serde(from = "u32", into = "u32")]
+pub enum ErrorCode {
+    Timeout,
+    NodeNotFound,
+    NotSupported,
+    TemporarilyUnavailable,
+    MalformedRequest,
+    Crash,
+    Abort,
+    KeyDoesNotExist,
+    KeyAlreadyExists,
+    PreconditionFailed,
+    TxnConflict,
+    Custom(u32),
+}
+
+impl ErrorCode {
+    /// Definite errors guarantee that the request had no effect, whereas
+    /// indefinite errors (timeouts and crashes) may or may not have taken
+    /// effect.
+    pub fn is_definite(&self) -> bool {
+        !matches!(self, Self::Timeout | Self::Crash)
+    }
+}
+
+impl From<u32> for ErrorCode {
+    fn from(code: u32) -> Self {
+        match code {
+            0 => Self::Timeout,
+            1 => Self::NodeNotFound,
+            10 => Self::NotSupported,
+            11 => Self::TemporarilyUnavailable,
+            12 => Self::MalformedRequest,
+            13 => Self::Crash,
+            14 => Self::Abort,
+            20 => Self::KeyDoesNotExist,
+            21 => Self::KeyAlreadyExists,
+            22 => Self::PreconditionFailed,
+            30 => Self::TxnConflict,
+            code => Self::Custom(code),
+        }
+    }
+}
+
+impl From<ErrorCode> for u32 {
+    fn from(code: ErrorCode) -> Self {
+        match code {
+            ErrorCode::Timeout => 0,
+            ErrorCode::NodeNotFound => 1,
+            ErrorCode::NotSupported => 10,
+            ErrorCode::TemporarilyUnavailable => 11,
+            ErrorCode::MalformedRequest => 12,
+            ErrorCode::Crash => 13,
+            ErrorCode::Abort => 14,
+            ErrorCode::KeyDoesNotExist => 20,
+            ErrorCode::KeyAlreadyExists => 21,
+            ErrorCode::PreconditionFailed => 22,
+            ErrorCode::TxnConflict => 30,
+            ErrorCode::Custom(code) => code,
+        }
+    }
+}
+
+impl fmt::Display for ErrorCode {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let name = match self {
+            Self::Timeout => "timeout",
+            Self::NodeNotFound => "node-not-found",
+            Self::NotSupported => "not-supported",
+            Self::TemporarilyUnavailable => "temporarily-unavailable",
+            Self::MalformedRequest => "malformed-request",
+            Self::Crash => "crash",
+            Self::Abort => "abort",
+            Self::KeyDoesNotExist => "key-does-not-exist",
+            Self::KeyAlreadyExists => "key-already-exists",
+            Self::PreconditionFailed => "precondition-failed",
+            Self::TxnConflict => "txn-conflict",
+            Self::Custom(code) => return write!(f, "error {code}"),
+        };
+        f.write_str(name)
+    }
+}
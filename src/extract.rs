@@ -0,0 +1,209 @@
+//! Types that can be taken as handler arguments, in the style of axum's
+//! extractors. A handler can take any combination of them, in any order.
+
+use {
+    crate::{
+        error::{Error, ErrorCode},
+        node::NodeContext,
+        protocol::{Body, ErrorBody, Message, MessageId, NodeId},
+    },
+    serde::de::DeserializeOwned,
+    std::{
+        cell::Cell,
+        rc::Rc,
+        sync::{
+            atomic::{AtomicBool, Ordering},
+            Arc,
+        },
+    },
+};
+
+/// Everything an extractor can draw on for a single inbound message.
+pub struct RequestParts {
+    pub message: Message,
+    pub context: NodeContext,
+    responder_taken: Rc<Cell<bool>>,
+    responder: Option<Arc<AtomicBool>>,
+}
+
+impl RequestParts {
+    pub(crate) fn new(message: Message, context: NodeContext) -> Self {
+        Self {
+            message,
+            context,
+            responder_taken: Rc::default(),
+            responder: None,
+        }
+    }
+
+    /// Called once every extractor has succeeded. Until then a `Responder`
+    /// doesn't count as taken, so that a failure to extract a later argument
+    /// is still replied to automatically.
+    pub(crate) fn finish_extracting(&mut self) {
+        if let Some(armed) = self.responder.take() {
+            armed.store(true, Ordering::Relaxed);
+            self.responder_taken.set(true);
+        }
+    }
+
+    /// Whether a handler has taken a `Responder`, and so will reply itself.
+    pub(crate) fn responder_taken(&self) -> Rc<Cell<bool>> {
+        Rc::clone(&self.responder_taken)
+    }
+}
+
+pub trait FromMessage<S>: Sized {
+    fn from_message(parts: &mut RequestParts, state: &S) -> Result<Self, Error>;
+}
+
+impl<S> FromMessage<S> for NodeContext {
+    fn from_message(parts: &mut RequestParts, _: &S) -> Result<Self, Error> {
+        Ok(parts.context.clone())
+    }
+}
+
+/// The node that sent the message.
+#[derive(Debug, Clone)]
+pub struct Src(pub NodeId);
+
+impl<S> FromMessage<S> for Src {
+    fn from_message(parts: &mut RequestParts, _: &S) -> Result<Self, Error> {
+        Ok(Src(parts.message.src.clone()))
+    }
+}
+
+/// The message's `msg_id`. Rejects messages that don't have one; take an
+/// `Option<MsgId>` to accept those too.
+#[derive(Debug, Copy, Clone)]
+pub struct MsgId(pub MessageId);
+
+impl<S> FromMessage<S> for MsgId {
+    fn from_message(parts: &mut RequestParts, _: &S) -> Result<Self, Error> {
+        parts
+            .message
+            .msg_id()
+            .map(MsgId)
+            .ok_or_else(|| Error::new(ErrorCode::MalformedRequest, "missing msg_id"))
+    }
+}
+
+impl<S, T> FromMessage<S> for Option<T>
+where
+    T: FromMessage<S>,
+{
+    fn from_message(parts: &mut RequestParts, state: &S) -> Result<Self, Error> {
+        Ok(T::from_message(parts, state).ok())
+    }
+}
+
+/// The node's state, as returned by its `on_init` hook, or any part of it
+/// that implements [`FromRef`].
+#[derive(Debug, Clone)]
+pub struct State<T>(pub T);
+
+impl<S, T> FromMessage<S> for State<T>
+where
+    T: FromRef<S>,
+{
+    fn from_message(_: &mut RequestParts, state: &S) -> Result<Self, Error> {
+        Ok(State(T::from_ref(state)))
+    }
+}
+
+/// Derives a piece of a node's state from the whole of it, so that handlers
+/// can take `State<T>` for just the part they use.
+pub trait FromRef<T> {
+    fn from_ref(input: &T) -> Self;
+}
+
+impl<T> FromRef<T> for T
+where
+    T: Clone,
+{
+    fn from_ref(input: &T) -> Self {
+        input.clone()
+    }
+}
+
+/// The message body, deserialized. A body that doesn't fit `T` is rejected
+/// as a malformed request.
+#[derive(Debug, Clone)]
+pub struct Json<T>(pub T);
+
+impl<S, T> FromMessage<S> for Json<T>
+where
+    T: DeserializeOwned,
+{
+    fn from_message(parts: &mut RequestParts, _: &S) -> Result<Self, Error> {
+        serde_json::from_value(parts.message.body.clone())
+            .map(Json)
+            .map_err(|error| Error::new(ErrorCode::MalformedRequest, error.to_string()))
+    }
+}
+
+/// The whole message, as it was received.
+#[derive(Debug, Clone)]
+pub struct RawMessage(pub Message);
+
+impl<S> FromMessage<S> for RawMessage {
+    fn from_message(parts: &mut RequestParts, _: &S) -> Result<Self, Error> {
+        Ok(RawMessage(parts.message.clone()))
+    }
+}
+
+/// A handle for replying to the message later, from any task. Taking one
+/// turns off the automatic reply, so the handler's return value (or error)
+/// is not sent. Messages without a `msg_id` expect no reply, so replying to
+/// them does nothing.
+#[derive(Debug)]
+pub struct Responder {
+    message: Option<Message>,
+    context: NodeContext,
+    armed: Arc<AtomicBool>,
+}
+
+impl Responder {
+    pub async fn reply(mut self, body: impl Body) -> Result<(), Error> {
+        match self.message.take() {
+            Some(message) if message.msg_id().is_some() => self.context.reply(message, body).await,
+            _ => Ok(()),
+        }
+    }
+
+    pub async fn reply_error(self, error: &Error) -> Result<(), Error> {
+        self.reply(ErrorBody::from(error)).await
+    }
+}
+
+impl Drop for Responder {
+    fn drop(&mut self) {
+        if !self.armed.load(Ordering::Relaxed) {
+            return;
+        }
+
+        if let Some(message) = self.message.take().filter(|m| m.msg_id().is_some()) {
+            eprintln!(
+                "responder for {:?} from {} dropped without replying",
+                message.msg_id(),
+                message.src
+            );
+        }
+    }
+}
+
+impl<S> FromMessage<S> for Responder {
+    fn from_message(parts: &mut RequestParts, _: &S) -> Result<Self, Error> {
+        if parts.responder.is_some() {
+            return Err(Error::new(ErrorCode::Crash, "responder already taken"));
+        }
+
+        let armed = Arc::new(AtomicBool::new(false));
+        parts.responder = Some(Arc::clone(&armed));
+
+        Ok(Responder {
+            message: Some(parts.message.clone()),
+            context: parts.context.clone(),
+            armed,
+        })
+    }
+}
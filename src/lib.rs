@@ -1,6 +1,12 @@
+extern crate self as gossip_glomers;
+
+pub mod clock;
 pub mod error;
-mod io;
+pub mod extract;
 pub mod node;
 pub mod protocol;
+pub mod retry;
 pub mod server;
-mod utils;
+#[cfg(feature = "simulation")]
+pub mod simulation;
+pub mod transport;
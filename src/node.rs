@@ -1,36 +1,91 @@
 use {
     crate::{
-        error::Error,
-        protocol::{Message, MessageBody, MessageId, MessageIdGenerator, NodeId},
+        clock::{Clock, Elapsed},
+        error::{Error, ErrorCode},
+        extract::{FromMessage, FromRef, RequestParts},
+        protocol::{
+            insert_field, Body, ErrorBody, Init, Message, MessageId, MessageIdGenerator, NodeId,
+            Rpc,
+        },
     },
     futures::{
-        future::{BoxFuture, LocalBoxFuture},
-        FutureExt,
+        future::{self, BoxFuture, LocalBoxFuture},
+        stream::{FuturesUnordered, StreamExt},
+        FutureExt, TryFutureExt,
     },
-    serde::{de::DeserializeOwned, Serialize},
+    serde_json::Value,
     std::{
+        cell::RefCell,
         collections::HashMap,
         future::Future,
+        panic::AssertUnwindSafe,
+        rc::Rc,
         sync::{Arc, Mutex},
         task::{Context, Poll},
+        time::Duration,
     },
-    tokio::sync::oneshot,
-    tower::Service,
+    tokio::sync::{
+        mpsc::{self, error::TrySendError},
+        oneshot,
+    },
+    tokio_util::{sync::CancellationToken, task::TaskTracker},
+    tower::{util::UnsyncBoxService, BoxError, Layer, Service, ServiceExt},
 };
 
 pub struct Node<State> {
-    state: State,
+    init: Box<InitFn<State>>,
     router: Router<State>,
+    clock: Clock,
+    rpc_timeout: Duration,
+    shutdown: Option<Box<ShutdownFn<State>>>,
+    tasks: Vec<Box<TaskFn<State>>>,
+    restart_on_panic: bool,
+}
+
+pub(crate) struct Parts<State> {
+    pub init: Box<InitFn<State>>,
+    pub router: Router<State>,
+    pub clock: Clock,
+    pub rpc_timeout: Duration,
+    pub shutdown: Option<Box<ShutdownFn<State>>>,
+    pub tasks: Vec<Box<TaskFn<State>>>,
+    pub restart_on_panic: bool,
 }
 
-type HandlerFn<State> = dyn Fn(
-    NodeContext,
-    State,
-    Message,
-) -> Result<LocalBoxFuture<'static, Result<(), Error>>, Error>;
+const DEFAULT_RPC_TIMEOUT: Duration = Duration::from_secs(1);
+
+type InitFn<State> = dyn FnOnce(&NodeContext, &Init) -> Result<State, Error>;
+
+type TaskFn<State> = dyn FnOnce(&NodeContext, &State, bool);
+
+type ShutdownFn<State> =
+    dyn FnOnce(NodeContext, State) -> LocalBoxFuture<'static, Result<(), Error>>;
+
+type HandlerFn<State> =
+    dyn Fn(RequestParts, &State) -> LocalBoxFuture<'static, Result<(), BoxError>>;
+
+type LayerFn = dyn Fn(MessageService) -> MessageService;
+
+type UnackedMessages = Mutex<HashMap<MessageId, oneshot::Sender<Message>>>;
+
+/// The type-erased service that inbound messages are handled by, and that
+/// layers added with `Node::layer` and `Node::route_layer` wrap.
+pub type MessageService = UnsyncBoxService<Message, (), BoxError>;
 
 pub struct Router<State> {
-    routes: HashMap<&'static str, Arc<HandlerFn<State>>>,
+    routes: HashMap<&'static str, Route<State>>,
+    fallback: Option<Route<State>>,
+    layers: Vec<Rc<LayerFn>>,
+    /// Layers added with `route_layer` before their route, waiting for it.
+    pending_layers: HashMap<String, Vec<Rc<LayerFn>>>,
+    /// The first message type that was routed twice. The builder methods
+    /// can't fail, so this is reported by `merge` and when the node is served.
+    duplicate: Option<String>,
+}
+
+struct Route<State> {
+    handler: Box<HandlerFn<State>>,
+    layers: Vec<Rc<LayerFn>>,
 }
 
 #[derive(Debug, Clone)]
@@ -39,16 +94,36 @@ pub struct NodeContext(Arc<SharedContext>);
 #[derive(Debug)]
 struct SharedContext {
     node_id: NodeId,
+    node_ids: Vec<NodeId>,
     message_id_generator: MessageIdGenerator,
-    unacked_messages: Arc<Mutex<HashMap<MessageId, oneshot::Sender<Result<Message, Error>>>>>,
+    unacked_messages: Arc<UnackedMessages>,
+    outgoing: mpsc::Sender<Message>,
+    clock: Clock,
+    rpc_timeout: Duration,
+    tasks: TaskTracker,
+    shutdown: CancellationToken,
 }
 
 impl NodeContext {
-    pub(crate) fn new(node_id: NodeId) -> Self {
+    /// A context for a node initialised with `init`, whose messages are
+    /// queued on `outgoing`. `Server` builds one of these for each node; it is
+    /// only needed directly to drive a `Router` with `Router::into_service`.
+    pub fn new(
+        init: Init,
+        outgoing: mpsc::Sender<Message>,
+        clock: Clock,
+        rpc_timeout: Duration,
+    ) -> Self {
         Self(Arc::new(SharedContext {
-            node_id,
+            node_id: init.node_id,
+            node_ids: init.node_ids,
             message_id_generator: MessageIdGenerator::default(),
             unacked_messages: Arc::default(),
+            outgoing,
+            clock,
+            rpc_timeout,
+            tasks: TaskTracker::new(),
+            shutdown: CancellationToken::new(),
         }))
     }
 
@@ -56,38 +131,280 @@ impl NodeContext {
         self.0.node_id.clone()
     }
 
+    /// Every node in the cluster, including this one, as listed in `init`.
+    pub fn node_ids(&self) -> &[NodeId] {
+        &self.0.node_ids
+    }
+
+    /// The position of this node in `node_ids`.
+    pub fn node_index(&self) -> Option<usize> {
+        self.0
+            .node_ids
+            .iter()
+            .position(|node_id| *node_id == self.0.node_id)
+    }
+
+    /// Every other node in the cluster.
+    pub fn peers(&self) -> impl Iterator<Item = &NodeId> {
+        self.0
+            .node_ids
+            .iter()
+            .filter(move |node_id| **node_id != self.0.node_id)
+    }
+
+    /// The server nodes in the cluster, including this one, ordered by their
+    /// number, so `n2` comes before `n10`.
+    pub fn server_nodes(&self) -> Vec<NodeId> {
+        let mut server_nodes: Vec<_> = self
+            .0
+            .node_ids
+            .iter()
+            .filter(|node_id| node_id.is_server_node())
+            .cloned()
+            .collect();
+        server_nodes.sort_by_cached_key(|node_id| {
+            let number = node_id.to_string()[1..].parse::<u64>().ok();
+            (number, node_id.clone())
+        });
+        server_nodes
+    }
+
+    pub fn clock(&self) -> &Clock {
+        &self.0.clock
+    }
+
+    /// Runs a background task that the server tracks, and cancels when it
+    /// shuts down. The name identifies the task in logs.
+    pub fn spawn<F>(&self, name: impl Into<String>, task: F)
+    where
+        F: Future<Output = ()> + Send + 'static,
+    {
+        let name = name.into();
+        let shutdown = self.0.shutdown.clone();
+        self.0.tasks.spawn(async move {
+            tokio::select! {
+                _ = shutdown.cancelled() => {}
+                result = AssertUnwindSafe(task).catch_unwind() => {
+                    if result.is_err() {
+                        eprintln!("task {name} panicked");
+                    }
+                }
+            }
+        });
+    }
+
+    pub(crate) async fn cancel_tasks(&self) {
+        self.0.shutdown.cancel();
+        self.0.tasks.close();
+        self.0.tasks.wait().await;
+    }
+
     pub fn send_to(&self, node: NodeId) -> NodeService {
         NodeService {
             dst: node,
-            msg_id: None,
+            timeout: self.0.rpc_timeout,
             context: self.clone(),
         }
     }
 
-    pub async fn reply(
+    pub async fn call<Request>(
+        &self,
+        node: NodeId,
+        request: Request,
+    ) -> Result<Request::Response, Error>
+    where
+        Request: Rpc,
+    {
+        self.call_with_timeout(node, request, self.0.rpc_timeout)
+            .await
+    }
+
+    pub async fn call_with_timeout<Request>(
+        &self,
+        node: NodeId,
+        request: Request,
+        timeout: Duration,
+    ) -> Result<Request::Response, Error>
+    where
+        Request: Rpc,
+    {
+        let response = self.send_to(node).timeout(timeout).oneshot(request).await?;
+
+        match response.msg_type() {
+            Some(msg_type) if msg_type == Request::Response::TYPE => {
+                Ok(serde_json::from_value(response.body)?)
+            }
+            Some(msg_type) if msg_type == ErrorBody::TYPE => {
+                Err(serde_json::from_value::<ErrorBody>(response.body)?.into())
+            }
+            msg_type => Err(Error::UnexpectedResponse(
+                msg_type.unwrap_or("<none>").to_owned(),
+            )),
+        }
+    }
+
+    /// Sends `request` to every one of `nodes` and waits for `quorum` of them
+    /// to reply successfully, returning those replies. Fails as soon as too
+    /// many have failed or timed out for a quorum to be possible. Requests
+    /// still outstanding once the outcome is known are abandoned. The error's
+    /// code is only definite if every request was definitely refused, or if
+    /// `quorum` is more than the number of nodes, when nothing is sent.
+    pub async fn quorum_call<Request>(
+        &self,
+        nodes: impl IntoIterator<Item = NodeId>,
+        request: Request,
+        quorum: usize,
+        timeout: Duration,
+    ) -> Result<Vec<(NodeId, Request::Response)>, Error>
+    where
+        Request: Rpc + Clone,
+    {
+        let mut calls: FuturesUnordered<_> = nodes
+            .into_iter()
+            .map(|node| {
+                let call = self.call_with_timeout(node.clone(), request.clone(), timeout);
+                async move { (node, call.await) }
+            })
+            .collect();
+        if quorum > calls.len() {
+            return Err(Error::new(
+                ErrorCode::MalformedRequest,
+                format!("quorum of {quorum} larger than node count {}", calls.len()),
+            ));
+        }
+
+        let mut replies = Vec::new();
+        let mut failures = Vec::new();
+        while replies.len() < quorum && replies.len() + calls.len() >= quorum {
+            match calls.next().await {
+                Some((node, Ok(response))) => replies.push((node, response)),
+                Some((node, Err(error))) => {
+                    eprintln!("quorum call to {node} failed: {error}");
+                    failures.push(error.code());
+                }
+                None => break,
+            }
+        }
+
+        if replies.len() < quorum {
+            return Err(Error::QuorumNotReached {
+                replies: replies.len(),
+                quorum,
+                failures,
+                pending: calls.len(),
+            });
+        }
+        Ok(replies)
+    }
+
+    /// Sends `request` to every one of `nodes`, returning the first
+    /// successful reply.
+    pub async fn first_success<Request>(
+        &self,
+        nodes: impl IntoIterator<Item = NodeId>,
+        request: Request,
+        timeout: Duration,
+    ) -> Result<(NodeId, Request::Response), Error>
+    where
+        Request: Rpc + Clone,
+    {
+        let mut replies = self.quorum_call(nodes, request, 1, timeout).await?;
+        Ok(replies.remove(0))
+    }
+
+    /// Sends `body` to `node` without a `msg_id`, so no reply is expected
+    /// and nothing is kept around waiting for one.
+    pub async fn send(&self, node: NodeId, body: impl Body) -> Result<(), Error> {
+        self.send_message(Message {
+            src: self.node_id().clone(),
+            dest: node,
+            body: body.to_value()?,
+        })
+        .await
+    }
+
+    /// Like `send`, but fails with `TemporarilyUnavailable` instead of
+    /// waiting when the outgoing queue is full, for messages that are better
+    /// dropped than delayed, such as gossip that will be repeated anyway.
+    pub fn try_send(&self, node: NodeId, body: impl Body) -> Result<(), Error> {
+        let message = Message {
+            src: self.node_id().clone(),
+            dest: node,
+            body: body.to_value()?,
+        };
+
+        self.0
+            .outgoing
+            .try_send(message)
+            .map_err(|error| match error {
+                TrySendError::Full(_) => {
+                    Error::new(ErrorCode::TemporarilyUnavailable, "outgoing queue is full")
+                }
+                TrySendError::Closed(_) => Error::TransportClosed,
+            })
+    }
+
+    /// Like `send`, to each of `nodes`.
+    pub async fn send_all(
+        &self,
+        nodes: impl IntoIterator<Item = NodeId>,
+        body: impl Body,
+    ) -> Result<(), Error> {
+        let body = body.to_value()?;
+
+        for node in nodes {
+            self.send_message(Message {
+                src: self.node_id().clone(),
+                dest: node,
+                body: body.clone(),
+            })
+            .await?;
+        }
+        Ok(())
+    }
+
+    pub async fn reply(&self, message: Message, reply_body: impl Body) -> Result<(), Error> {
+        self.reply_with_value(message, reply_body.to_value()?).await
+    }
+
+    pub(crate) async fn reply_with_value(
         &self,
         message: Message,
-        reply_body: impl Into<MessageBody>,
+        mut reply_body: Value,
     ) -> Result<(), Error> {
-        let mut reply_body = serde_json::to_value(reply_body.into())?;
-        reply_body["msg_id"] = serde_json::to_value(self.0.message_id_generator.next_id())?;
+        insert_field(
+            &mut reply_body,
+            "msg_id",
+            serde_json::to_value(self.0.message_id_generator.next_id())?,
+        )?;
 
         if let Some(msg_id) = message.msg_id() {
-            reply_body["in_reply_to"] = serde_json::to_value(msg_id)?;
+            insert_field(
+                &mut reply_body,
+                "in_reply_to",
+                serde_json::to_value(msg_id)?,
+            )?;
         }
 
-        Message {
+        self.send_message(Message {
             src: self.node_id().clone(),
             dest: message.src,
             body: reply_body,
-        }
-        .send()
-        .await?;
+        })
+        .await
+    }
 
-        Ok(())
+    /// Queues a message for the writer, waiting if the queue is full so that
+    /// a slow transport pushes back on the handlers producing output.
+    pub(crate) async fn send_message(&self, message: Message) -> Result<(), Error> {
+        self.0
+            .outgoing
+            .send(message)
+            .await
+            .map_err(|_| Error::TransportClosed)
     }
 
-    fn register(&self, msg_id: MessageId) -> oneshot::Receiver<Result<Message, Error>> {
+    fn register(&self, msg_id: MessageId) -> oneshot::Receiver<Message> {
         let (sender, receiver) = oneshot::channel();
         self.0
             .unacked_messages
@@ -97,28 +414,40 @@ impl NodeContext {
         receiver
     }
 
-    fn acknowledge(&self, msg_id: MessageId, message: Message) {
-        if let Some(sender) = self.0.unacked_messages.lock().unwrap().remove(&msg_id) {
-            let _ = sender.send(Ok(message));
-        }
+    fn unregister(&self, msg_id: MessageId) {
+        self.0.unacked_messages.lock().unwrap().remove(&msg_id);
     }
 
-    fn cancel(&self, msg_id: MessageId) {
-        eprintln!("message cancelled: {msg_id:?}");
-
+    pub(crate) fn acknowledge(&self, msg_id: MessageId, message: Message) {
         if let Some(sender) = self.0.unacked_messages.lock().unwrap().remove(&msg_id) {
-            let _ = sender.send(Err(Error::RequestCancelled));
+            let _ = sender.send(message);
         }
     }
 }
 
+/// Sends requests to a single node. Each call is tracked on its own, so
+/// several can be in flight at once, and dropping a call's future stops
+/// waiting for its reply.
+#[derive(Clone)]
 pub struct NodeService {
     dst: NodeId,
-    msg_id: Option<MessageId>,
+    timeout: Duration,
     context: NodeContext,
 }
 
-impl Service<MessageBody> for NodeService {
+impl NodeService {
+    /// Overrides the node's `rpc_timeout` for requests sent through this
+    /// service.
+    pub fn timeout(mut self, timeout: Duration) -> Self {
+        self.timeout = timeout;
+        self
+    }
+}
+
+impl<Request> Service<Request> for NodeService
+where
+    Request: Body,
+{
     type Response = Message;
     type Error = Error;
     type Future = BoxFuture<'static, Result<Message, Error>>;
@@ -127,44 +456,82 @@ impl Service<MessageBody> for NodeService {
         Poll::Ready(Ok(()))
     }
 
-    fn call(&mut self, req: MessageBody) -> Self::Future {
-        if let Some(msg_id) = self
-            .msg_id
-            .replace(self.context.0.message_id_generator.next_id())
-        {
-            self.context.cancel(msg_id);
-        }
-
-        let msg_id = self.msg_id.expect("should have a msg id");
-
+    fn call(&mut self, req: Request) -> Self::Future {
+        let msg_id = self.context.0.message_id_generator.next_id();
         let receiver = self.context.register(msg_id);
+        let pending = PendingReply {
+            msg_id,
+            context: self.context.clone(),
+        };
 
-        let src = self.context.node_id();
+        let context = self.context.clone();
+        let src = context.node_id();
         let dest = self.dst.clone();
+        let body = req.to_value();
+        let timeout = self.timeout;
         async move {
-            let mut body = serde_json::to_value(req)?;
-            body["msg_id"] = serde_json::to_value(msg_id)?;
+            let mut body = body?;
+            insert_field(&mut body, "msg_id", serde_json::to_value(msg_id)?)?;
+
+            context.send_message(Message { src, dest, body }).await?;
+            let response = match context.clock().timeout(timeout, receiver).await {
+                Ok(response) => response.map_err(|_err| Error::RequestCancelled),
+                Err(Elapsed) => Err(Error::Timeout),
+            };
 
-            Message { src, dest, body }.send().await?;
-            let response = receiver.await.map_err(|_err| Error::RequestCancelled)??;
-            Ok(response)
+            // Moved into the future, so abandoning the call removes the entry.
+            drop(pending);
+            response
         }
         .boxed()
     }
 }
 
-impl Drop for NodeService {
+/// Stops waiting for a reply when dropped, whether the call finished, timed
+/// out, or was abandoned.
+struct PendingReply {
+    msg_id: MessageId,
+    context: NodeContext,
+}
+
+impl Drop for PendingReply {
     fn drop(&mut self) {
-        if let Some(msg_id) = self.msg_id.take() {
-            self.context.cancel(msg_id);
-        }
+        self.context.unregister(self.msg_id);
     }
 }
 
-pub trait Handler<Request, Response, State> {
-    type Future: Future<Output = Result<Response, Error>>;
+/// What a handler can return: a body to reply with, or `()` for handlers
+/// that don't reply, or that reply themselves through a `Responder`.
+pub trait IntoReply {
+    fn into_reply(self) -> Result<Option<Value>, Error>;
+}
 
-    fn call(&self, context: NodeContext, state: State, request: Request) -> Self::Future;
+impl<T> IntoReply for T
+where
+    T: Body,
+{
+    fn into_reply(self) -> Result<Option<Value>, Error> {
+        self.to_value().map(Some)
+    }
+}
+
+impl IntoReply for () {
+    fn into_reply(self) -> Result<Option<Value>, Error> {
+        Ok(None)
+    }
+}
+
+/// An async function whose arguments are all extractors (see
+/// [`crate::extract`]). `Args` is only there to tell the implementations for
+/// each arity apart.
+pub trait Handler<Args, State> {
+    type Response;
+
+    fn call(
+        &self,
+        parts: RequestParts,
+        state: &State,
+    ) -> LocalBoxFuture<'static, Result<Self::Response, Error>>;
 }
 
 impl Default for Node<()> {
@@ -173,83 +540,621 @@ impl Default for Node<()> {
     }
 }
 
-impl<State> Node<State> {
+impl<State> Node<State>
+where
+    State: 'static,
+{
     pub fn with_state(state: State) -> Self {
+        Self::on_init(move |_, _| Ok(state))
+    }
+
+    /// Builds the node's state once `init` has arrived, so that it can depend
+    /// on the node's ID and its peers. If this fails, the error is sent back
+    /// in reply to `init` and the server stops.
+    pub fn on_init(
+        init: impl FnOnce(&NodeContext, &Init) -> Result<State, Error> + 'static,
+    ) -> Self {
         Self {
-            state,
-            router: Router {
-                routes: HashMap::new(),
-            },
+            init: Box::new(init),
+            router: Router::new(),
+            clock: Clock::default(),
+            rpc_timeout: DEFAULT_RPC_TIMEOUT,
+            shutdown: None,
+            tasks: Vec::new(),
+            restart_on_panic: false,
         }
     }
 
-    pub fn add_handler<Request, Response>(
+    /// Runs `task` every `period`, starting once the node has been
+    /// initialised and stopping when it shuts down. A panic stops the task
+    /// unless `restart_on_panic` is set, in which case it runs again on the
+    /// next tick.
+    pub fn add_periodic_task<F, Fut>(
         mut self,
-        msg_type: &'static str,
-        handler: impl Handler<Request, Response, State> + 'static,
+        name: &'static str,
+        period: Duration,
+        task: F,
     ) -> Self
     where
-        State: 'static,
-        Request: DeserializeOwned + 'static,
-        Response: Into<MessageBody> + Serialize + 'static,
+        State: Clone + Send,
+        F: Fn(NodeContext, State) -> Fut + Send + 'static,
+        Fut: Future<Output = ()> + Send + 'static,
+    {
+        self.tasks
+            .push(Box::new(move |context, state, restart_on_panic| {
+                let state = state.clone();
+                let task_context = context.clone();
+
+                context.spawn(name, async move {
+                    let mut interval = task_context.clock().interval(period);
+                    loop {
+                        interval.tick().await;
+
+                        let tick = task(task_context.clone(), state.clone());
+                        if AssertUnwindSafe(tick).catch_unwind().await.is_err() {
+                            eprintln!("task {name} panicked");
+                            if !restart_on_panic {
+                                break;
+                            }
+                        }
+                    }
+                });
+            }));
+        self
+    }
+
+    /// Whether periodic tasks keep running after a panic.
+    pub fn restart_on_panic(mut self, restart_on_panic: bool) -> Self {
+        self.restart_on_panic = restart_on_panic;
+        self
+    }
+
+    /// Runs once the server has stopped, after in-flight handlers have
+    /// finished and background tasks have been cancelled, but before output
+    /// is flushed. Use this to persist state.
+    pub fn on_shutdown<F, Fut>(mut self, shutdown: F) -> Self
+    where
+        F: FnOnce(NodeContext, State) -> Fut + 'static,
+        Fut: Future<Output = Result<(), Error>> + 'static,
+    {
+        self.shutdown = Some(Box::new(move |context, state| {
+            shutdown(context, state).boxed_local()
+        }));
+        self
+    }
+
+    pub fn clock(mut self, clock: Clock) -> Self {
+        self.clock = clock;
+        self
+    }
+
+    /// How long requests sent with `NodeContext::call` or `send_to` wait for
+    /// a reply before failing with `Error::Timeout`, unless overridden.
+    pub fn rpc_timeout(mut self, timeout: Duration) -> Self {
+        self.rpc_timeout = timeout;
+        self
+    }
+
+    /// Handles messages of type `msg_type` with `handler`. Adding a second
+    /// handler for the same type, including one added by `nest`, makes the
+    /// server fail to start with `Error::DuplicateRoute`.
+    pub fn add_handler<H, Args>(mut self, msg_type: &'static str, handler: H) -> Self
+    where
+        H: Handler<Args, State> + 'static,
+        H::Response: IntoReply + 'static,
+    {
+        self.router = self.router.route(msg_type, handler);
+        self
+    }
+
+    pub fn fallback<H, Args>(mut self, handler: H) -> Self
+    where
+        H: Handler<Args, State> + 'static,
+        H::Response: IntoReply + 'static,
+    {
+        self.router = self.router.fallback(handler);
+        self
+    }
+
+    /// Wraps every handler, including the fallback, in `layer`. Layers added
+    /// later wrap those added earlier.
+    pub fn layer<L>(mut self, layer: L) -> Self
+    where
+        L: Layer<MessageService> + 'static,
+        L::Service: Service<Message, Response = ()> + 'static,
+        <L::Service as Service<Message>>::Error: Into<BoxError>,
+        <L::Service as Service<Message>>::Future: 'static,
+    {
+        self.router = self.router.layer(layer);
+        self
+    }
+
+    /// Wraps the handler for `msg_type` in `layer`. The handler can be added
+    /// before or after, but if it never is, the server fails to start with
+    /// `Error::MissingRoute`.
+    pub fn route_layer<L>(mut self, msg_type: &str, layer: L) -> Self
+    where
+        L: Layer<MessageService> + 'static,
+        L::Service: Service<Message, Response = ()> + 'static,
+        <L::Service as Service<Message>>::Error: Into<BoxError>,
+        <L::Service as Service<Message>>::Future: 'static,
+    {
+        self.router = self.router.route_layer(msg_type, layer);
+        self
+    }
+
+    /// Adds the routes of a router written against part of this node's
+    /// state, such as a reusable module with its own state. Fails if it
+    /// routes a message type that this node already does.
+    pub fn nest<Sub>(mut self, router: Router<Sub>) -> Result<Self, Error>
+    where
+        Sub: FromRef<State> + 'static,
+    {
+        self.router = self.router.merge(router.map_state(Sub::from_ref))?;
+        Ok(self)
+    }
+
+    pub(crate) fn into_parts(self) -> Parts<State> {
+        Parts {
+            init: self.init,
+            router: self.router,
+            clock: self.clock,
+            rpc_timeout: self.rpc_timeout,
+            shutdown: self.shutdown,
+            tasks: self.tasks,
+            restart_on_panic: self.restart_on_panic,
+        }
+    }
+}
+
+impl<State> Default for Router<State> {
+    fn default() -> Self {
+        Self {
+            routes: HashMap::new(),
+            fallback: None,
+            layers: Vec::new(),
+            pending_layers: HashMap::new(),
+            duplicate: None,
+        }
+    }
+}
+
+impl<State> Router<State>
+where
+    State: 'static,
+{
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Handles messages of type `msg_type` with `handler`. Routing a type
+    /// that already has a handler keeps the first one, and the conflict is
+    /// reported by `merge` or when the node is served.
+    pub fn route<H, Args>(mut self, msg_type: &'static str, handler: H) -> Self
+    where
+        H: Handler<Args, State> + 'static,
+        H::Response: IntoReply + 'static,
     {
-        let handler = move |context: NodeContext, state: State, message: Message| {
-            let request: Request = serde_json::from_value(message.body.clone())?;
-            let future = handler.call(context.clone(), state, request);
+        if self.routes.contains_key(msg_type) {
+            self.duplicate.get_or_insert_with(|| msg_type.to_owned());
+        } else {
+            let mut route = Route::from_handler(handler);
+            route.layers = self.pending_layers.remove(msg_type).unwrap_or_default();
+            self.routes.insert(msg_type, route);
+        }
+        self
+    }
 
-            Ok(async move {
-                let response = future.await?;
+    /// Handles messages that no route matches. Like `route`, setting it twice
+    /// is a conflict.
+    pub fn fallback<H, Args>(mut self, handler: H) -> Self
+    where
+        H: Handler<Args, State> + 'static,
+        H::Response: IntoReply + 'static,
+    {
+        if self.fallback.is_some() {
+            self.duplicate.get_or_insert_with(|| "fallback".to_owned());
+        } else {
+            self.fallback = Some(Route::from_handler(handler));
+        }
+        self
+    }
 
-                if message.msg_id().is_some() {
-                    context.reply(message, response).await?;
+    pub fn layer<L>(mut self, layer: L) -> Self
+    where
+        L: Layer<MessageService> + 'static,
+        L::Service: Service<Message, Response = ()> + 'static,
+        <L::Service as Service<Message>>::Error: Into<BoxError>,
+        <L::Service as Service<Message>>::Future: 'static,
+    {
+        self.layers.push(boxed_layer(layer));
+        self
+    }
+
+    pub fn route_layer<L>(mut self, msg_type: &str, layer: L) -> Self
+    where
+        L: Layer<MessageService> + 'static,
+        L::Service: Service<Message, Response = ()> + 'static,
+        <L::Service as Service<Message>>::Error: Into<BoxError>,
+        <L::Service as Service<Message>>::Future: 'static,
+    {
+        let layer = boxed_layer(layer);
+        match self.routes.get_mut(msg_type) {
+            Some(route) => route.layers.push(layer),
+            None => self
+                .pending_layers
+                .entry(msg_type.to_owned())
+                .or_default()
+                .push(layer),
+        }
+        self
+    }
+
+    /// Combines two routers into one. The layers added to `other` with
+    /// `Router::layer` keep wrapping only its own routes, each separately.
+    /// Fails if both route the same message type, or both have a fallback,
+    /// or if either already had a conflict of its own.
+    pub fn merge(mut self, other: Router<State>) -> Result<Self, Error> {
+        if let Some(msg_type) = self.duplicate.take().or(other.duplicate) {
+            return Err(Error::DuplicateRoute(msg_type));
+        }
+        if let Some(msg_type) = other
+            .routes
+            .keys()
+            .find(|msg_type| self.routes.contains_key(*msg_type))
+        {
+            return Err(Error::DuplicateRoute(msg_type.to_string()));
+        }
+        if self.fallback.is_some() && other.fallback.is_some() {
+            return Err(Error::DuplicateRoute("fallback".to_owned()));
+        }
+
+        let scope = |mut route: Route<State>| {
+            route.layers.extend(other.layers.iter().cloned());
+            route
+        };
+
+        for (msg_type, route) in other.routes {
+            self.routes.insert(msg_type, scope(route));
+        }
+        if let Some(fallback) = other.fallback {
+            self.fallback = Some(scope(fallback));
+        }
+
+        for (msg_type, layers) in other.pending_layers {
+            self.pending_layers
+                .entry(msg_type)
+                .or_default()
+                .extend(layers);
+        }
+        let routes = &mut self.routes;
+        self.pending_layers
+            .retain(|msg_type, layers| match routes.get_mut(msg_type.as_str()) {
+                Some(route) => {
+                    route.layers.append(layers);
+                    false
                 }
+                None => true,
+            });
+        Ok(self)
+    }
 
-                Ok(())
-            }
-            .boxed_local())
+    /// Reports the first message type that was routed twice, or that has
+    /// route layers but was never routed, if any.
+    pub(crate) fn check(&self) -> Result<(), Error> {
+        if let Some(msg_type) = &self.duplicate {
+            return Err(Error::DuplicateRoute(msg_type.clone()));
+        }
+        match self.pending_layers.keys().next() {
+            Some(msg_type) => Err(Error::MissingRoute(msg_type.clone())),
+            None => Ok(()),
+        }
+    }
+
+    fn map_state<Outer>(self, map: fn(&Outer) -> State) -> Router<Outer>
+    where
+        Outer: 'static,
+    {
+        let map_route = |route: Route<State>| Route {
+            handler: Box::new(move |parts: RequestParts, state: &Outer| {
+                (route.handler)(parts, &map(state))
+            }),
+            layers: route.layers,
         };
 
-        self.router.routes.insert(msg_type, Arc::new(handler));
-        self
+        Router {
+            routes: self
+                .routes
+                .into_iter()
+                .map(|(msg_type, route)| (msg_type, map_route(route)))
+                .collect(),
+            fallback: self.fallback.map(map_route),
+            layers: self.layers,
+            pending_layers: self.pending_layers,
+            duplicate: self.duplicate,
+        }
     }
 
-    pub(crate) fn into_parts(self) -> (State, Router<State>) {
-        (self.state, self.router)
+    /// Binds the router to a node's context and state, once it has been
+    /// initialised, giving the service that handles its inbound messages.
+    /// `Server` does this itself; call it to drive a router directly or put
+    /// it behind a tower stack of your own. Fails if a message type was routed
+    /// twice, or has route layers but no route.
+    pub fn into_service(self, context: NodeContext, state: State) -> Result<MessageService, Error> {
+        self.check()?;
+        let state = Rc::new(state);
+
+        let bind = |route: Route<State>| {
+            let handler = HandlerService {
+                handler: route.handler,
+                context: context.clone(),
+                state: Rc::clone(&state),
+            };
+            let service = apply_layers(UnsyncBoxService::new(handler), route.layers);
+            Rc::new(RefCell::new(service))
+        };
+
+        let fallback = self.fallback.unwrap_or_else(|| {
+            Route::new(|parts: RequestParts, _: &State| not_supported(parts.context, parts.message))
+        });
+
+        let router = RouterService {
+            routes: self
+                .routes
+                .into_iter()
+                .map(|(msg_type, route)| (msg_type, bind(route)))
+                .collect(),
+            fallback: bind(fallback),
+        };
+
+        let service = apply_layers(UnsyncBoxService::new(router), self.layers);
+        Ok(UnsyncBoxService::new(ReplyOnError {
+            inner: service,
+            context,
+        }))
     }
 }
 
-impl<State> Router<State> {
-    pub fn handle(
-        &self,
-        context: &NodeContext,
-        state: &State,
-        message: Message,
-    ) -> Result<LocalBoxFuture<Result<(), Error>>, Error>
+impl<State> Route<State>
+where
+    State: 'static,
+{
+    /// Replies with whatever `handler` returns, unless the request has no
+    /// `msg_id` or the handler took a `Responder` to reply itself.
+    fn from_handler<H, Args>(handler: H) -> Self
     where
-        State: Clone + 'static,
+        H: Handler<Args, State> + 'static,
+        H::Response: IntoReply + 'static,
     {
-        if let Some(in_reply_to) = message.in_reply_to() {
-            context.acknowledge(in_reply_to, message.clone());
+        Self::new(move |parts: RequestParts, state: &State| {
+            let context = parts.context.clone();
+            let message = parts.message.clone();
+            let responder_taken = parts.responder_taken();
+            let response = handler.call(parts, state);
+            let auto_reply = !responder_taken.get() && message.msg_id().is_some();
+
+            async move {
+                let result = response.await.and_then(IntoReply::into_reply);
+                if !auto_reply {
+                    return result.map(drop).map_err(|error| Replied(error).into());
+                }
+
+                match result {
+                    Ok(Some(body)) => Ok(context.reply_with_value(message, body).await?),
+                    Ok(None) => Ok(()),
+                    Err(error) => {
+                        context.reply(message, ErrorBody::from(&error)).await?;
+                        Err(Replied(error).into())
+                    }
+                }
+            }
+            .boxed_local()
+        })
+    }
+
+    fn new(
+        handler: impl Fn(RequestParts, &State) -> LocalBoxFuture<'static, Result<(), BoxError>>
+            + 'static,
+    ) -> Self {
+        Self {
+            handler: Box::new(handler),
+            layers: Vec::new(),
         }
+    }
+}
+
+fn boxed_layer<L>(layer: L) -> Rc<LayerFn>
+where
+    L: Layer<MessageService> + 'static,
+    L::Service: Service<Message, Response = ()> + 'static,
+    <L::Service as Service<Message>>::Error: Into<BoxError>,
+    <L::Service as Service<Message>>::Future: 'static,
+{
+    Rc::new(move |service| UnsyncBoxService::new(layer.layer(service).map_err(Into::into)))
+}
+
+fn apply_layers(service: MessageService, layers: Vec<Rc<LayerFn>>) -> MessageService {
+    layers
+        .into_iter()
+        .fold(service, |service, layer| layer(service))
+}
 
-        match message
+/// A single handler, bound to the node's context and state.
+struct HandlerService<State> {
+    handler: Box<HandlerFn<State>>,
+    context: NodeContext,
+    state: Rc<State>,
+}
+
+impl<State> Service<Message> for HandlerService<State> {
+    type Response = ();
+    type Error = BoxError;
+    type Future = LocalBoxFuture<'static, Result<(), BoxError>>;
+
+    fn poll_ready(&mut self, _: &mut Context<'_>) -> Poll<Result<(), Self::Error>> {
+        Poll::Ready(Ok(()))
+    }
+
+    fn call(&mut self, message: Message) -> Self::Future {
+        let parts = RequestParts::new(message, self.context.clone());
+        (self.handler)(parts, &self.state).err_into().boxed_local()
+    }
+}
+
+/// Dispatches each message to the route for its type. Routes are polled for
+/// readiness when a message for them arrives, rather than up front, so a
+/// route that is applying back-pressure only holds up its own messages.
+struct RouterService {
+    routes: HashMap<&'static str, Rc<RefCell<MessageService>>>,
+    fallback: Rc<RefCell<MessageService>>,
+}
+
+impl Service<Message> for RouterService {
+    type Response = ();
+    type Error = BoxError;
+    type Future = LocalBoxFuture<'static, Result<(), BoxError>>;
+
+    fn poll_ready(&mut self, _: &mut Context<'_>) -> Poll<Result<(), Self::Error>> {
+        Poll::Ready(Ok(()))
+    }
+
+    fn call(&mut self, message: Message) -> Self::Future {
+        let route = match message
             .msg_type()
             .and_then(|msg_type| self.routes.get(msg_type))
         {
-            Some(handler) => handler(context.clone(), state.clone(), message),
-            None => Err(Error::NotImplemented),
+            Some(route) => Rc::clone(route),
+            // Replies that nobody routed are expected (they complete an
+            // outstanding request), so they never reach the fallback.
+            None if message.in_reply_to().is_some() => return future::ok(()).boxed_local(),
+            None => Rc::clone(&self.fallback),
+        };
+
+        async move {
+            future::poll_fn(|cx| route.borrow_mut().poll_ready(cx)).await?;
+            let response = route.borrow_mut().call(message);
+            response.await
         }
+        .boxed_local()
     }
 }
 
-impl<T, Request, Response, State, Fut> Handler<Request, Response, State> for T
-where
-    T: Fn(NodeContext, State, Request) -> Fut,
-    Fut: Future<Output = Result<Response, Error>>,
-{
-    type Future = Fut;
+/// An error that has already been replied to, or whose request expects no
+/// reply, so `ReplyOnError` leaves it alone.
+#[derive(Debug, thiserror::Error)]
+#[error(transparent)]
+struct Replied(Error);
+
+/// Handlers reply to their own errors, but an error from a layer (a timeout,
+/// or a layer rejecting the request) would otherwise leave the sender without
+/// a reply. Also completes the calls that replies are for.
+struct ReplyOnError {
+    inner: MessageService,
+    context: NodeContext,
+}
 
-    fn call(&self, context: NodeContext, state: State, request: Request) -> Self::Future {
-        self(context, state, request)
+impl Service<Message> for ReplyOnError {
+    type Response = ();
+    type Error = BoxError;
+    type Future = LocalBoxFuture<'static, Result<(), BoxError>>;
+
+    fn poll_ready(&mut self, cx: &mut Context<'_>) -> Poll<Result<(), Self::Error>> {
+        self.inner.poll_ready(cx)
     }
+
+    fn call(&mut self, message: Message) -> Self::Future {
+        // `Server` acknowledges replies as soon as they arrive, but a router
+        // driven directly only sees them here.
+        if let Some(in_reply_to) = message.in_reply_to() {
+            self.context.acknowledge(in_reply_to, message.clone());
+        }
+
+        let context = self.context.clone();
+        let response = self.inner.call(message.clone());
+
+        async move {
+            let error = match response.await {
+                Ok(()) => return Ok(()),
+                Err(error) if error.is::<Replied>() => return Err(error),
+                Err(error) => error,
+            };
+
+            if message.msg_id().is_some() {
+                let body = match error.downcast_ref::<Error>() {
+                    Some(error) => ErrorBody::from(error),
+                    None if error.is::<tower::timeout::error::Elapsed>() => {
+                        ErrorBody::from(&Error::new(ErrorCode::Timeout, error.to_string()))
+                    }
+                    None => ErrorBody::from(&Error::new(ErrorCode::Crash, error.to_string())),
+                };
+                context.reply(message, body).await?;
+            }
+
+            Err(error)
+        }
+        .boxed_local()
+    }
+}
+
+fn not_supported(
+    context: NodeContext,
+    message: Message,
+) -> LocalBoxFuture<'static, Result<(), BoxError>> {
+    async move {
+        let error = Error::new(
+            ErrorCode::NotSupported,
+            format!(
+                "unsupported message type: {}",
+                message.msg_type().unwrap_or("<none>")
+            ),
+        );
+
+        if message.msg_id().is_some() && message.msg_type() != Some("error") {
+            context.reply(message, ErrorBody::from(&error)).await?;
+        }
+
+        Err(Replied(error).into())
+    }
+    .boxed_local()
 }
+
+macro_rules! impl_handler {
+    ($($ty:ident),*) => {
+        #[allow(non_snake_case, unused_variables, unused_mut)]
+        impl<F, Fut, Response, State, $($ty,)*> Handler<($($ty,)*), State> for F
+        where
+            F: Fn($($ty),*) -> Fut,
+            Fut: Future<Output = Result<Response, Error>> + 'static,
+            Response: 'static,
+            $($ty: FromMessage<State>,)*
+        {
+            type Response = Response;
+
+            fn call(
+                &self,
+                mut parts: RequestParts,
+                state: &State,
+            ) -> LocalBoxFuture<'static, Result<Response, Error>> {
+                $(
+                    let $ty = match $ty::from_message(&mut parts, state) {
+                        Ok(value) => value,
+                        Err(error) => return future::err(error).boxed_local(),
+                    };
+                )*
+
+                parts.finish_extracting();
+                self($($ty),*).boxed_local()
+            }
+        }
+    };
+}
+
+impl_handler!();
+impl_handler!(T1);
+impl_handler!(T1, T2);
+impl_handler!(T1, T2, T3);
+impl_handler!(T1, T2, T3, T4);
+impl_handler!(T1, T2, T3, T4, T5);
+impl_handler!(T1, T2, T3, T4, T5, T6);
+impl_handler!(T1, T2, T3, T4, T5, T6, T7);
+impl_handler!(T1, T2, T3, T4, T5, T6, T7, T8);
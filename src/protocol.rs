@@ -1,19 +1,21 @@
+pub use gossip_glomers_derive::MaelstromMessage;
+
 use {
-    crate::error::Error,
-    serde::{Deserialize, Serialize},
+    crate::error::{Error, ErrorCode},
+    serde::{de::DeserializeOwned, Deserialize, Serialize},
     serde_json::Value,
     std::{
         collections::HashMap,
+        fmt,
         sync::{
             atomic::{AtomicU64, Ordering},
             Arc,
         },
     },
-    tokio::io::{self, AsyncWriteExt},
     uuid::Uuid,
 };
 
-#[derive(Debug, Clone, Eq, PartialEq, Hash, Serialize, Deserialize)]
+#[derive(Debug, Clone, Eq, PartialEq, Ord, PartialOrd, Hash, Serialize, Deserialize)]
 pub struct NodeId(String);
 
 impl NodeId {
@@ -26,6 +28,24 @@ impl NodeId {
     }
 }
 
+impl fmt::Display for NodeId {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(&self.0)
+    }
+}
+
+impl From<&str> for NodeId {
+    fn from(node_id: &str) -> Self {
+        Self(node_id.to_owned())
+    }
+}
+
+impl From<String> for NodeId {
+    fn from(node_id: String) -> Self {
+        Self(node_id)
+    }
+}
+
 #[derive(Debug, Default, Copy, Clone, Eq, PartialEq, Hash, Serialize, Deserialize)]
 pub struct MessageId(u64);
 
@@ -64,176 +84,120 @@ impl Message {
             .and_then(Value::as_u64)
             .map(MessageId)
     }
+}
 
-    pub async fn send(self) -> Result<(), Error> {
-        let mut json = serde_json::to_string(&self)?;
-        json.push('\n');
+/// A message body that is identified on the wire by its `type` field, usually
+/// implemented with `#[derive(MaelstromMessage)]`.
+pub trait Body: Serialize + DeserializeOwned {
+    const TYPE: &'static str;
 
-        let mut std_out = io::stdout();
-        std_out.write_all(json.as_bytes()).await?;
-        Ok(())
+    fn to_value(&self) -> Result<Value, Error> {
+        let mut value = serde_json::to_value(self)?;
+        insert_field(&mut value, "type", Value::from(Self::TYPE))?;
+        Ok(value)
     }
 }
 
-#[derive(Debug, Clone, Serialize, Deserialize)]
+/// Sets a field on a message body, failing rather than panicking if the body
+/// isn't a JSON object.
+pub(crate) fn insert_field(body: &mut Value, key: &str, value: Value) -> Result<(), Error> {
+    body.as_object_mut()
+        .ok_or(Error::BodyNotAnObject)?
+        .insert(key.to_owned(), value);
+    Ok(())
+}
+
+/// A request message that expects a reply of type `Response`.
+pub trait Rpc: Body {
+    type Response: Body;
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize, MaelstromMessage)]
+#[message(type = "init")]
 pub struct Init {
     pub node_id: NodeId,
     pub node_ids: Vec<NodeId>,
 }
 
-#[derive(Debug, Clone, Serialize, Deserialize)]
+#[derive(Debug, Clone, Serialize, Deserialize, MaelstromMessage)]
+#[message(type = "init_ok", reply_to = Init)]
 pub struct InitOk {}
 
-#[derive(Debug, Clone, Serialize, Deserialize)]
+#[derive(Debug, Clone, Serialize, Deserialize, MaelstromMessage)]
+#[message(type = "echo")]
 pub struct Echo {
     pub echo: Value,
 }
 
-#[derive(Debug, Clone, Serialize, Deserialize)]
+#[derive(Debug, Clone, Serialize, Deserialize, MaelstromMessage)]
+#[message(type = "echo_ok", reply_to = Echo)]
 pub struct EchoOk {
     pub echo: Value,
 }
 
-#[derive(Debug, Clone, Serialize, Deserialize)]
+#[derive(Debug, Clone, Serialize, Deserialize, MaelstromMessage)]
+#[message(type = "generate")]
 pub struct Generate {}
 
-#[derive(Debug, Clone, Serialize, Deserialize)]
+#[derive(Debug, Clone, Serialize, Deserialize, MaelstromMessage)]
+#[message(type = "generate_ok", reply_to = Generate)]
 pub struct GenerateOk {
     pub id: Uuid,
 }
 
-#[derive(Debug, Clone, Serialize, Deserialize)]
+#[derive(Debug, Clone, Serialize, Deserialize, MaelstromMessage)]
+#[message(type = "broadcast")]
 pub struct Broadcast {
     pub message: Value,
 }
 
-#[derive(Debug, Clone, Serialize, Deserialize)]
+#[derive(Debug, Clone, Serialize, Deserialize, MaelstromMessage)]
+#[message(type = "broadcast_ok", reply_to = Broadcast)]
 pub struct BroadcastOk {}
 
-#[derive(Debug, Clone, Serialize, Deserialize)]
+#[derive(Debug, Clone, Serialize, Deserialize, MaelstromMessage)]
+#[message(type = "read")]
 pub struct Read {}
 
-#[derive(Debug, Clone, Serialize, Deserialize)]
+#[derive(Debug, Clone, Serialize, Deserialize, MaelstromMessage)]
+#[message(type = "read_ok", reply_to = Read)]
 pub struct ReadOk {
     pub messages: Value,
 }
 
-#[derive(Debug, Clone, Serialize, Deserialize)]
+#[derive(Debug, Clone, Serialize, Deserialize, MaelstromMessage)]
+#[message(type = "topology")]
 pub struct Topology {
     pub topology: HashMap<NodeId, Vec<NodeId>>,
 }
 
-#[derive(Debug, Clone, Serialize, Deserialize)]
+#[derive(Debug, Clone, Serialize, Deserialize, MaelstromMessage)]
+#[message(type = "topology_ok", reply_to = Topology)]
 pub struct TopologyOk {}
 
-#[derive(Debug, Clone, Serialize, Deserialize)]
-#[serde(tag = "type")]
-pub enum MessageBody {
-    #[serde(rename = "init")]
-    Init(Init),
-
-    #[serde(rename = "init_ok")]
-    InitOk(InitOk),
-
-    #[serde(rename = "echo")]
-    Echo(Echo),
-
-    #[serde(rename = "echo_ok")]
-    EchoOk(EchoOk),
-
-    #[serde(rename = "generate")]
-    Generate(Generate),
-
-    #[serde(rename = "generate_ok")]
-    GenerateOk(GenerateOk),
-
-    #[serde(rename = "broadcast")]
-    Broadcast(Broadcast),
-
-    #[serde(rename = "broadcast_ok")]
-    BroadcastOk(BroadcastOk),
-
-    #[serde(rename = "read")]
-    Read(Read),
-
-    #[serde(rename = "read_ok")]
-    ReadOk(ReadOk),
-
-    #[serde(rename = "topology")]
-    Topology(Topology),
-
-    #[serde(rename = "topology_ok")]
-    TopologyOk(TopologyOk),
-}
-
-impl From<Init> for MessageBody {
-    fn from(init: Init) -> Self {
-        Self::Init(init)
-    }
-}
-
-impl From<InitOk> for MessageBody {
-    fn from(init_ok: InitOk) -> Self {
-        Self::InitOk(init_ok)
-    }
-}
-
-impl From<Echo> for MessageBody {
-    fn from(echo: Echo) -> Self {
-        Self::Echo(echo)
-    }
-}
-
-impl From<EchoOk> for MessageBody {
-    fn from(echo_ok: EchoOk) -> Self {
-        Self::EchoOk(echo_ok)
-    }
+#[derive(Debug, Clone, Serialize, Deserialize, MaelstromMessage)]
+#[message(type = "error")]
+pub struct ErrorBody {
+    pub code: ErrorCode,
+    pub text: String,
 }
 
-impl From<Generate> for MessageBody {
-    fn from(generate: Generate) -> Self {
-        Self::Generate(generate)
-    }
-}
-
-impl From<GenerateOk> for MessageBody {
-    fn from(generate_ok: GenerateOk) -> Self {
-        Self::GenerateOk(generate_ok)
-    }
-}
-
-impl From<Broadcast> for MessageBody {
-    fn from(broadcast: Broadcast) -> Self {
-        Self::Broadcast(broadcast)
-    }
-}
-
-impl From<BroadcastOk> for MessageBody {
-    fn from(broadcast_ok: BroadcastOk) -> Self {
-        Self::BroadcastOk(broadcast_ok)
-    }
-}
-
-impl From<Read> for MessageBody {
-    fn from(read: Read) -> Self {
-        Self::Read(read)
-    }
-}
-
-impl From<ReadOk> for MessageBody {
-    fn from(read_ok: ReadOk) -> Self {
-        Self::ReadOk(read_ok)
-    }
-}
+impl From<&Error> for ErrorBody {
+    fn from(error: &Error) -> Self {
+        let text = match error {
+            Error::Maelstrom { text, .. } => text.clone(),
+            error => error.to_string(),
+        };
 
-impl From<Topology> for MessageBody {
-    fn from(topology: Topology) -> Self {
-        Self::Topology(topology)
+        Self {
+            code: error.code(),
+            text,
+        }
     }
 }
 
-impl From<TopologyOk> for MessageBody {
-    fn from(topology_ok: TopologyOk) -> Self {
-        Self::TopologyOk(topology_ok)
+impl From<ErrorBody> for Error {
+    fn from(ErrorBody { code, text }: ErrorBody) -> Self {
+        Error::Maelstrom { code, text }
     }
 }
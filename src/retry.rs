@@ -0,0 +1,225 @@
+//! Retrying requests to other nodes. `ExponentialBackoff` is a `tower` retry
+//! policy, so a `NodeService` is wrapped in it with `tower::retry::Retry` or
+//! `RetryLayer`.
+
+use {
+    crate::{
+        clock::Clock,
+        error::{Error, ErrorCode},
+        protocol::{Body, ErrorBody, Message},
+    },
+    futures::future::{BoxFuture, FutureExt},
+    rand::{rngs::StdRng, Rng, SeedableRng},
+    std::{
+        sync::{Arc, Mutex},
+        time::Duration,
+    },
+    tokio::time::Instant,
+    tower::retry::{budget::Budget, Policy},
+};
+
+const DEFAULT_ATTEMPTS: usize = 3;
+const DEFAULT_BASE_DELAY: Duration = Duration::from_millis(50);
+const DEFAULT_MAX_DELAY: Duration = Duration::from_secs(1);
+
+/// How much randomness to add to each delay, so that nodes that failed
+/// together don't all retry together. See
+/// <https://aws.amazon.com/blogs/architecture/exponential-backoff-and-jitter/>.
+#[derive(Debug, Copy, Clone, Eq, PartialEq)]
+pub enum Jitter {
+    /// Exactly the exponential delay.
+    None,
+    /// Anywhere between zero and the exponential delay.
+    Full,
+    /// Anywhere between the base delay and three times the previous delay.
+    Decorrelated,
+}
+
+type RetryableFn = dyn Fn(&Error) -> bool + Send + Sync;
+
+#[derive(Clone)]
+pub struct ExponentialBackoff {
+    attempts_remaining: usize,
+    base_delay: Duration,
+    max_delay: Duration,
+    previous_delay: Duration,
+    attempt: u32,
+    jitter: Jitter,
+    retry_window: Option<Duration>,
+    give_up_at: Option<Instant>,
+    budget: Option<Arc<Budget>>,
+    retryable: Arc<RetryableFn>,
+    rng: Arc<Mutex<StdRng>>,
+    clock: Clock,
+}
+
+impl ExponentialBackoff {
+    pub fn new(clock: Clock) -> Self {
+        Self {
+            attempts_remaining: DEFAULT_ATTEMPTS,
+            base_delay: DEFAULT_BASE_DELAY,
+            max_delay: DEFAULT_MAX_DELAY,
+            previous_delay: DEFAULT_BASE_DELAY,
+            attempt: 0,
+            jitter: Jitter::Full,
+            retry_window: None,
+            give_up_at: None,
+            budget: None,
+            retryable: Arc::new(is_retryable),
+            rng: Arc::new(Mutex::new(StdRng::from_entropy())),
+            clock,
+        }
+    }
+
+    /// How many times to retry after the first attempt.
+    pub fn attempts(mut self, attempts: usize) -> Self {
+        self.attempts_remaining = attempts;
+        self
+    }
+
+    /// The delay before the first retry, doubling for each one after.
+    pub fn base_delay(mut self, delay: Duration) -> Self {
+        self.base_delay = delay;
+        self.previous_delay = delay;
+        self
+    }
+
+    pub fn max_delay(mut self, delay: Duration) -> Self {
+        self.max_delay = delay;
+        self
+    }
+
+    pub fn jitter(mut self, jitter: Jitter) -> Self {
+        self.jitter = jitter;
+        self
+    }
+
+    /// Gives up once retrying would go on for longer than `window`. The
+    /// window opens when the first attempt fails, not when it was sent, as a
+    /// policy doesn't see requests start: a request can take as long as its
+    /// first attempt plus `window`, so bound that attempt with a timeout too.
+    pub fn retry_window(mut self, window: Duration) -> Self {
+        self.retry_window = Some(window);
+        self
+    }
+
+    /// Limits retries to a proportion of requests, shared by every clone of
+    /// this policy, so that an overloaded peer isn't buried in them.
+    pub fn budget(mut self, budget: Budget) -> Self {
+        self.budget = Some(Arc::new(budget));
+        self
+    }
+
+    /// Decides which errors are worth retrying, instead of `is_retryable`.
+    pub fn retry_if(mut self, retryable: impl Fn(&Error) -> bool + Send + Sync + 'static) -> Self {
+        self.retryable = Arc::new(retryable);
+        self
+    }
+
+    /// Seeds the jitter, for reproducible delays.
+    pub fn seed(mut self, seed: u64) -> Self {
+        self.rng = Arc::new(Mutex::new(StdRng::seed_from_u64(seed)));
+        self
+    }
+
+    fn next_delay(&self) -> Duration {
+        let exponential = self
+            .base_delay
+            .saturating_mul(2u32.saturating_pow(self.attempt))
+            .min(self.max_delay);
+        let mut rng = self.rng.lock().unwrap();
+
+        match self.jitter {
+            Jitter::None => exponential,
+            Jitter::Full => rng.gen_range(Duration::ZERO..=exponential),
+            Jitter::Decorrelated => {
+                let upper = self.previous_delay.saturating_mul(3).max(self.base_delay);
+                rng.gen_range(self.base_delay..=upper).min(self.max_delay)
+            }
+        }
+    }
+}
+
+impl<Req> Policy<Req, Message, Error> for ExponentialBackoff
+where
+    Req: Clone,
+{
+    type Future = BoxFuture<'static, Self>;
+
+    fn retry(&self, _: &Req, result: Result<&Message, &Error>) -> Option<Self::Future> {
+        // The first result is seen once per request, whatever its outcome.
+        if self.attempt == 0 {
+            if let Some(budget) = &self.budget {
+                budget.deposit();
+            }
+        }
+
+        // `NodeService` hands back error replies like any other reply.
+        let reply_error;
+        let error = match result {
+            Ok(reply) if reply.msg_type() == Some(ErrorBody::TYPE) => {
+                reply_error = serde_json::from_value::<ErrorBody>(reply.body.clone())
+                    .map_or_else(Error::from, Error::from);
+                &reply_error
+            }
+            Ok(_) => return None,
+            Err(error) => error,
+        };
+        if self.attempts_remaining == 0 || !(self.retryable)(error) {
+            return None;
+        }
+
+        let now = self.clock.now();
+        let give_up_at = self
+            .give_up_at
+            .or_else(|| self.retry_window.map(|window| now + window));
+        let delay = self.next_delay();
+        if give_up_at.is_some_and(|give_up_at| now + delay > give_up_at) {
+            return None;
+        }
+
+        if let Some(budget) = &self.budget {
+            budget.withdraw().ok()?;
+        }
+
+        eprintln!(
+            "{error}, retrying in {}ms, {} attempts remain",
+            delay.as_millis(),
+            self.attempts_remaining
+        );
+
+        let next = Self {
+            attempts_remaining: self.attempts_remaining - 1,
+            previous_delay: delay,
+            attempt: self.attempt + 1,
+            give_up_at,
+            ..self.clone()
+        };
+
+        Some(
+            async move {
+                next.clock.sleep(delay).await;
+                next
+            }
+            .boxed(),
+        )
+    }
+
+    fn clone_request(&self, req: &Req) -> Option<Req> {
+        Some(req.clone())
+    }
+}
+
+/// Whether a request that failed with `error` might succeed if it were sent
+/// again: timeouts, a lost connection, and the Maelstrom errors that say the
+/// node was unable to handle it rather than that it was refused.
+pub fn is_retryable(error: &Error) -> bool {
+    match error {
+        Error::Timeout | Error::TransportClosed | Error::RequestCancelled => true,
+        Error::Maelstrom { code, .. } => matches!(
+            code,
+            ErrorCode::Timeout | ErrorCode::TemporarilyUnavailable | ErrorCode::Crash
+        ),
+        _ => false,
+    }
+}
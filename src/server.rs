@@ -1,44 +1,149 @@
 use {
     crate::{
-        error::Error,
-        io::messages_from_std_in,
-        node::{Node, NodeContext},
-        protocol::{Init, InitOk},
+        error::{Error, ErrorCode},
+        node::{MessageService, Node, NodeContext, Parts},
+        protocol::{insert_field, Body, ErrorBody, Init, InitOk, Message},
+        transport::{StdioTransport, Transport},
     },
-    futures::{stream::FuturesUnordered, stream::StreamExt},
+    futures::{
+        future::{self, LocalBoxFuture},
+        stream::{FuturesUnordered, Stream, StreamExt},
+        FutureExt, Sink, SinkExt, TryFutureExt,
+    },
+    std::{collections::VecDeque, time::Duration},
+    tokio::{
+        sync::{
+            mpsc::{self, error::TryRecvError},
+            oneshot,
+        },
+        task::JoinHandle,
+    },
+    tower::{BoxError, Service},
 };
 
-#[derive(Default)]
-pub struct Server;
+const OUTGOING_BUFFER: usize = 1024;
+const MAX_EARLY_MESSAGES: usize = 1024;
+const MAX_QUEUED_MESSAGES: usize = 1024;
+
+const DEFAULT_GRACE_PERIOD: Duration = Duration::from_secs(5);
+
+pub struct Server<T = StdioTransport> {
+    transport: T,
+    grace_period: Duration,
+}
+
+impl Default for Server<StdioTransport> {
+    fn default() -> Self {
+        Self::new(StdioTransport)
+    }
+}
+
+impl<T> Server<T>
+where
+    T: Transport,
+{
+    pub fn new(transport: T) -> Self {
+        Self {
+            transport,
+            grace_period: DEFAULT_GRACE_PERIOD,
+        }
+    }
+
+    /// How long to wait, once input has closed, for in-flight handlers and
+    /// queued output before giving up on them.
+    pub fn grace_period(mut self, grace_period: Duration) -> Self {
+        self.grace_period = grace_period;
+        self
+    }
 
-impl Server {
     pub async fn serve<State>(self, node: Node<State>) -> Result<(), Error>
     where
         State: Clone + 'static,
     {
-        let (state, router) = node.into_parts();
+        let Parts {
+            init: init_state,
+            router,
+            clock,
+            rpc_timeout,
+            shutdown,
+            tasks,
+            restart_on_panic,
+        } = node.into_parts();
+        router.check()?;
 
-        let mut incoming_messages = messages_from_std_in();
+        let (mut incoming_messages, outgoing_messages) = self.transport.split();
 
-        let message = incoming_messages
-            .next()
-            .await
-            .expect("no messages received");
+        let (outgoing_sender, outgoing_receiver) = mpsc::channel(OUTGOING_BUFFER);
+        let (stop_writer, writer_stopped) = oneshot::channel();
+        let writer = tokio::spawn(write_messages(
+            outgoing_receiver,
+            outgoing_messages,
+            writer_stopped,
+        ));
+
+        let mut early_messages = VecDeque::new();
+        let waited = wait_for_init(
+            &mut incoming_messages,
+            &outgoing_sender,
+            &mut early_messages,
+        );
+        let (message, init) = match waited.await {
+            Ok(init) => init,
+            Err(error) => {
+                finish_writing(stop_writer, writer).await?;
+                return Err(error);
+            }
+        };
 
-        let init: Init =
-            serde_json::from_value(message.body.clone()).expect("didn't receive an init message");
+        let context = NodeContext::new(init.clone(), outgoing_sender, clock.clone(), rpc_timeout);
+        let state = match init_state(&context, &init) {
+            Ok(state) => {
+                context.reply(message, InitOk {}).await?;
+                state
+            }
+            Err(error) => {
+                context.reply(message, ErrorBody::from(&error)).await?;
+                finish_writing(stop_writer, writer).await?;
+                return Err(error);
+            }
+        };
 
-        let context = NodeContext::new(init.node_id);
-        context.reply(message, InitOk {}).await?;
+        for task in tasks {
+            task(&context, &state, restart_on_panic);
+        }
 
+        let mut service = router.into_service(context.clone(), state.clone())?;
+
+        // Messages wait here until the service is ready for them. Replies are
+        // acknowledged as soon as they arrive, so a handler waiting on one is
+        // never held up behind a service that is applying back-pressure. Past
+        // a limit, messages are dropped rather than queued, as their senders
+        // will retry.
+        let mut queued = early_messages;
         let mut node_futures = FuturesUnordered::new();
+
         loop {
             tokio::select! {
+                biased;
+
+                ready = future::poll_fn(|cx| service.poll_ready(cx)), if !queued.is_empty() => {
+                    if !dispatch(ready, &mut service, &mut queued, &mut node_futures) {
+                        break;
+                    }
+                },
                 message = incoming_messages.next() => {
                     match message {
+                        Some(message) if message.msg_type() == Some(Init::TYPE) => {
+                            node_futures.push(duplicate_init(context.clone(), message).err_into().boxed_local());
+                        }
                         Some(message) => {
-                            if let Ok(future) = router.handle(&context, &state, message) {
-                                node_futures.push(future);
+                            if let Some(in_reply_to) = message.in_reply_to() {
+                                context.acknowledge(in_reply_to, message.clone());
+                            }
+                            if queued.len() < MAX_QUEUED_MESSAGES {
+                                queued.push_back(message);
+                            } else {
+                                eprintln!("dropping message, queue is full: {message:?}");
                             }
                         }
                         None => break,
@@ -60,6 +165,196 @@ impl Server {
             }
         }
 
-        Ok(())
+        // Input has closed, so shut down: let in-flight handlers finish, stop
+        // background tasks, give the user a chance to persist state, and then
+        // flush whatever output is still queued.
+        let deadline = clock.now() + self.grace_period;
+
+        let drain = async {
+            loop {
+                tokio::select! {
+                    biased;
+
+                    ready = future::poll_fn(|cx| service.poll_ready(cx)), if !queued.is_empty() => {
+                        if !dispatch(ready, &mut service, &mut queued, &mut node_futures) {
+                            break;
+                        }
+                    },
+                    Some(result) = node_futures.next(), if !node_futures.is_empty() => {
+                        if let Err(error) = result {
+                            eprintln!("error: {}", error);
+                        }
+                    },
+                    else => break,
+                }
+            }
+        };
+        if clock.timeout(self.grace_period, drain).await.is_err() {
+            eprintln!("grace period elapsed, abandoning in-flight handlers");
+        }
+        drop(node_futures);
+
+        context.cancel_tasks().await;
+
+        if let Some(shutdown) = shutdown {
+            if let Err(error) = shutdown(context, state).await {
+                eprintln!("error during shutdown: {}", error);
+            }
+        }
+
+        let remaining = deadline.saturating_duration_since(clock.now());
+        match clock
+            .timeout(remaining, finish_writing(stop_writer, writer))
+            .await
+        {
+            Ok(result) => result,
+            Err(_) => {
+                eprintln!("grace period elapsed, abandoning queued output");
+                Ok(())
+            }
+        }
+    }
+}
+
+/// Hands the next queued message to the service once it is ready, returning
+/// false if the service has failed and can't take any more.
+fn dispatch(
+    ready: Result<(), BoxError>,
+    service: &mut MessageService,
+    queued: &mut VecDeque<Message>,
+    node_futures: &mut FuturesUnordered<LocalBoxFuture<'static, Result<(), BoxError>>>,
+) -> bool {
+    if let Err(error) = ready {
+        eprintln!("service failed: {error}");
+        return false;
+    }
+
+    if let Some(message) = queued.pop_front() {
+        node_futures.push(service.call(message));
+    }
+    true
+}
+
+/// Waits for a valid `init` message. Anything else that arrives first is
+/// buffered, up to a limit, to be handled once the node is initialised. An
+/// `init` that doesn't parse is refused, so its sender isn't left waiting.
+async fn wait_for_init(
+    incoming_messages: &mut (impl Stream<Item = Message> + Unpin),
+    outgoing_messages: &mpsc::Sender<Message>,
+    early_messages: &mut VecDeque<Message>,
+) -> Result<(Message, Init), Error> {
+    while let Some(message) = incoming_messages.next().await {
+        if message.msg_type() != Some(Init::TYPE) {
+            if early_messages.len() < MAX_EARLY_MESSAGES {
+                early_messages.push_back(message);
+            } else {
+                eprintln!("dropping message received before init: {message:?}");
+            }
+            continue;
+        }
+
+        match serde_json::from_value(message.body.clone()) {
+            Ok(init) => return Ok((message, init)),
+            Err(error) => {
+                eprintln!("invalid init message: {error}");
+                if let Some(reply) = malformed_init_reply(message, &error)? {
+                    let _ = outgoing_messages.send(reply).await;
+                }
+            }
+        }
+    }
+
+    Err(Error::ClosedBeforeInit)
+}
+
+/// The reply to an `init` that doesn't parse, if it expects one. There is no
+/// `NodeContext` yet, so this is addressed from whichever node it was sent to.
+fn malformed_init_reply(
+    message: Message,
+    error: &serde_json::Error,
+) -> Result<Option<Message>, Error> {
+    let Some(msg_id) = message.msg_id() else {
+        return Ok(None);
+    };
+
+    let error = Error::new(ErrorCode::MalformedRequest, error.to_string());
+    let mut body = ErrorBody::from(&error).to_value()?;
+    insert_field(&mut body, "in_reply_to", serde_json::to_value(msg_id)?)?;
+    Ok(Some(Message {
+        src: message.dest,
+        dest: message.src,
+        body,
+    }))
+}
+
+/// Repeating the same `init` is harmless and is acknowledged again, but an
+/// `init` that would change the node's identity is refused.
+async fn duplicate_init(context: NodeContext, message: Message) -> Result<(), Error> {
+    let init: Init = match serde_json::from_value(message.body.clone()) {
+        Ok(init) => init,
+        Err(error) => {
+            let error = Error::new(ErrorCode::MalformedRequest, error.to_string());
+            if message.msg_id().is_some() {
+                context.reply(message, ErrorBody::from(&error)).await?;
+            }
+            return Err(error);
+        }
+    };
+
+    if init.node_id == context.node_id() {
+        return context.reply(message, InitOk {}).await;
+    }
+
+    let error = Error::new(
+        ErrorCode::PreconditionFailed,
+        format!("already initialised as {}", context.node_id()),
+    );
+    context.reply(message, ErrorBody::from(&error)).await?;
+    Err(error)
+}
+
+async fn finish_writing(
+    stop_writer: oneshot::Sender<()>,
+    writer: JoinHandle<Result<(), Error>>,
+) -> Result<(), Error> {
+    let _ = stop_writer.send(());
+    writer.await.map_err(|_| Error::TransportClosed)?
+}
+
+/// Writes every outgoing message from a single task, so that messages can't
+/// interleave. The sink is only flushed once there are no more messages
+/// immediately available, batching writes under load. After `stop` fires,
+/// messages already queued are written before the sink is closed.
+async fn write_messages(
+    mut messages: mpsc::Receiver<Message>,
+    mut sink: impl Sink<Message, Error = Error> + Unpin,
+    mut stop: oneshot::Receiver<()>,
+) -> Result<(), Error> {
+    let mut stopping = false;
+
+    loop {
+        let message = match messages.try_recv() {
+            Ok(message) => message,
+            Err(TryRecvError::Disconnected) => break,
+            Err(TryRecvError::Empty) => {
+                sink.flush().await?;
+
+                tokio::select! {
+                    message = messages.recv() => match message {
+                        Some(message) => message,
+                        None => break,
+                    },
+                    _ = &mut stop, if !stopping => {
+                        stopping = true;
+                        messages.close();
+                        continue;
+                    }
+                }
+            }
+        };
+
+        sink.feed(message).await?;
     }
+
+    sink.close().await
 }
@@ -0,0 +1,410 @@
+//! A deterministic, in-process network for testing nodes without Maelstrom.
+//!
+//! Nodes run on a single threaded runtime with a paused clock, so time only
+//! advances when every task is waiting on a timer. Every decision the network
+//! makes (latency, loss, duplication) is drawn from a seeded RNG, so the same
+//! seed reproduces the same run provided the nodes themselves are
+//! deterministic.
+
+use {
+    crate::{
+        error::Error,
+        node::Node,
+        protocol::{insert_field, Body, ErrorBody, Init, Message, NodeId, Rpc},
+        server::Server,
+        transport::MemoryTransport,
+    },
+    futures::{
+        channel::mpsc::{self, UnboundedSender},
+        future::LocalBoxFuture,
+        FutureExt, StreamExt,
+    },
+    rand::{rngs::StdRng, Rng, SeedableRng},
+    serde_json::Value,
+    std::{
+        cell::{Cell, RefCell},
+        collections::{HashMap, HashSet},
+        future::Future,
+        ops::Range,
+        rc::Rc,
+        time::Duration,
+    },
+    tokio::{
+        sync::oneshot,
+        task::{self, LocalSet},
+        time::{self, Instant},
+    },
+};
+
+type StartNode = Box<dyn FnOnce(MemoryTransport) -> LocalBoxFuture<'static, Result<(), Error>>>;
+
+pub struct Simulation {
+    seed: u64,
+    conditions: Conditions,
+    nodes: Vec<(NodeId, StartNode)>,
+}
+
+#[derive(Debug, Clone)]
+struct Conditions {
+    latency: Range<Duration>,
+    loss: f64,
+    duplication: f64,
+}
+
+impl Simulation {
+    pub fn new(seed: u64) -> Self {
+        Self {
+            seed,
+            conditions: Conditions {
+                latency: Duration::from_millis(1)..Duration::from_millis(5),
+                loss: 0.0,
+                duplication: 0.0,
+            },
+            nodes: Vec::new(),
+        }
+    }
+
+    /// Adds a node to the cluster. Keep a clone of the node's state (an
+    /// `Arc<Mutex<..>>`, say) to make assertions on it while the simulation
+    /// runs.
+    pub fn node<State>(mut self, node_id: impl Into<NodeId>, node: Node<State>) -> Self
+    where
+        State: Clone + 'static,
+    {
+        let start = move |transport| Server::new(transport).serve(node).boxed_local();
+        self.nodes.push((node_id.into(), Box::new(start)));
+        self
+    }
+
+    pub fn latency(mut self, latency: Range<Duration>) -> Self {
+        self.conditions.latency = latency;
+        self
+    }
+
+    pub fn loss(mut self, probability: f64) -> Self {
+        self.conditions.loss = probability;
+        self
+    }
+
+    pub fn duplication(mut self, probability: f64) -> Self {
+        self.conditions.duplication = probability;
+        self
+    }
+
+    /// Starts every node, sends each of them an `init` message, and then runs
+    /// `test` to completion against the cluster.
+    pub fn run<F, Fut>(self, test: F) -> Fut::Output
+    where
+        F: FnOnce(Cluster) -> Fut,
+        Fut: Future,
+    {
+        let runtime = tokio::runtime::Builder::new_current_thread()
+            .enable_all()
+            .start_paused(true)
+            .build()
+            .expect("failed to build simulation runtime");
+
+        LocalSet::new().block_on(&runtime, async move {
+            let cluster = Cluster(Rc::new(RefCell::new(Network {
+                rng: StdRng::seed_from_u64(self.seed),
+                conditions: self.conditions,
+                partitions: HashSet::new(),
+                inboxes: HashMap::new(),
+                history: Vec::new(),
+                started_at: Instant::now(),
+            })));
+
+            let node_ids: Vec<NodeId> = self.nodes.iter().map(|(id, _)| id.clone()).collect();
+
+            for (node_id, start) in self.nodes {
+                let (transport, handle) = MemoryTransport::new();
+                let (inbox, mut outbox) = handle.into_parts();
+
+                let init = Init {
+                    node_id: node_id.clone(),
+                    node_ids: node_ids.clone(),
+                };
+                let mut body = init.to_value().expect("init should serialize");
+                insert_field(&mut body, "msg_id", Value::from(0)).expect("init is an object");
+                let _ = inbox.unbounded_send(Message {
+                    src: NodeId::from("c0"),
+                    dest: node_id.clone(),
+                    body,
+                });
+
+                cluster
+                    .0
+                    .borrow_mut()
+                    .inboxes
+                    .insert(node_id.clone(), inbox);
+
+                task::spawn_local(async move {
+                    if let Err(error) = start(transport).await {
+                        eprintln!("{node_id:?} stopped: {error}");
+                    }
+                });
+
+                let cluster = cluster.clone();
+                task::spawn_local(async move {
+                    while let Some(message) = outbox.next().await {
+                        cluster.transmit(message);
+                    }
+                });
+            }
+
+            test(cluster).await
+        })
+    }
+}
+
+struct Network {
+    rng: StdRng,
+    conditions: Conditions,
+    partitions: HashSet<(NodeId, NodeId)>,
+    inboxes: HashMap<NodeId, UnboundedSender<Message>>,
+    history: Vec<Event>,
+    started_at: Instant,
+}
+
+impl Network {
+    /// Decides the fate of a message, returning the delay for each copy of
+    /// it that should be delivered.
+    fn schedule(&mut self, message: &Message) -> Vec<Duration> {
+        if self
+            .partitions
+            .contains(&(message.src.clone(), message.dest.clone()))
+            || self.rng.gen_bool(self.conditions.loss)
+        {
+            return Vec::new();
+        }
+
+        let copies = if self.rng.gen_bool(self.conditions.duplication) {
+            2
+        } else {
+            1
+        };
+
+        (0..copies)
+            .map(|_| {
+                let latency = self.conditions.latency.clone();
+                if latency.is_empty() {
+                    latency.start
+                } else {
+                    self.rng.gen_range(latency)
+                }
+            })
+            .collect()
+    }
+
+    fn deliver(&self, message: Message) {
+        if let Some(inbox) = self.inboxes.get(&message.dest) {
+            let _ = inbox.unbounded_send(message);
+        }
+    }
+
+    fn record(&mut self, kind: EventKind, client: &NodeId, node: &NodeId, body: Value) {
+        self.history.push(Event {
+            time: self.started_at.elapsed(),
+            client: client.clone(),
+            node: node.clone(),
+            kind,
+            body,
+        });
+    }
+}
+
+/// A handle to a running simulation, used to create clients, change network
+/// conditions and inspect the client-visible history.
+#[derive(Clone)]
+pub struct Cluster(Rc<RefCell<Network>>);
+
+impl Cluster {
+    pub fn client(&self, client_id: impl Into<NodeId>) -> Client {
+        let client_id = client_id.into();
+        let (inbox, mut replies) = mpsc::unbounded();
+        self.0.borrow_mut().inboxes.insert(client_id.clone(), inbox);
+
+        let pending = Rc::new(RefCell::new(HashMap::<u64, oneshot::Sender<Message>>::new()));
+        task::spawn_local({
+            let pending = Rc::clone(&pending);
+            async move {
+                while let Some(reply) = replies.next().await {
+                    let in_reply_to = reply.body.get("in_reply_to").and_then(Value::as_u64);
+                    if let Some(sender) =
+                        in_reply_to.and_then(|msg_id| pending.borrow_mut().remove(&msg_id))
+                    {
+                        let _ = sender.send(reply);
+                    }
+                }
+            }
+        });
+
+        Client {
+            client_id,
+            cluster: self.clone(),
+            pending,
+            next_msg_id: Rc::default(),
+            timeout: Duration::from_secs(1),
+        }
+    }
+
+    /// Drops every message sent between the two sides, in both directions,
+    /// until `heal` is called.
+    pub fn partition<A, B>(&self, side_a: A, side_b: B)
+    where
+        A: IntoIterator,
+        A::Item: Into<NodeId>,
+        B: IntoIterator,
+        B::Item: Into<NodeId>,
+    {
+        let side_b: Vec<NodeId> = side_b.into_iter().map(Into::into).collect();
+        let mut network = self.0.borrow_mut();
+
+        for a in side_a.into_iter().map(Into::into) {
+            for b in &side_b {
+                network.partitions.insert((a.clone(), b.clone()));
+                network.partitions.insert((b.clone(), a.clone()));
+            }
+        }
+    }
+
+    pub fn heal(&self) {
+        self.0.borrow_mut().partitions.clear();
+    }
+
+    pub fn set_latency(&self, latency: Range<Duration>) {
+        self.0.borrow_mut().conditions.latency = latency;
+    }
+
+    pub fn set_loss(&self, probability: f64) {
+        self.0.borrow_mut().conditions.loss = probability;
+    }
+
+    pub fn set_duplication(&self, probability: f64) {
+        self.0.borrow_mut().conditions.duplication = probability;
+    }
+
+    /// Virtual time elapsed since the simulation started.
+    pub fn elapsed(&self) -> Duration {
+        self.0.borrow().started_at.elapsed()
+    }
+
+    pub fn history(&self) -> Vec<Event> {
+        self.0.borrow().history.clone()
+    }
+
+    fn transmit(&self, message: Message) {
+        let delays = self.0.borrow_mut().schedule(&message);
+
+        for delay in delays {
+            let cluster = self.clone();
+            let message = message.clone();
+            task::spawn_local(async move {
+                time::sleep(delay).await;
+                cluster.0.borrow().deliver(message);
+            });
+        }
+    }
+}
+
+/// A simulated Maelstrom client. Its requests and their outcomes are recorded
+/// in the cluster's history.
+#[derive(Clone)]
+pub struct Client {
+    client_id: NodeId,
+    cluster: Cluster,
+    pending: Rc<RefCell<HashMap<u64, oneshot::Sender<Message>>>>,
+    next_msg_id: Rc<Cell<u64>>,
+    timeout: Duration,
+}
+
+impl Client {
+    pub fn with_timeout(mut self, timeout: Duration) -> Self {
+        self.timeout = timeout;
+        self
+    }
+
+    pub async fn call<Request>(
+        &self,
+        node: impl Into<NodeId>,
+        request: Request,
+    ) -> Result<Request::Response, Error>
+    where
+        Request: Rpc,
+    {
+        let node = node.into();
+        let msg_id = self.next_msg_id.replace(self.next_msg_id.get() + 1);
+
+        let mut body = request.to_value()?;
+        insert_field(&mut body, "msg_id", Value::from(msg_id))?;
+
+        let (sender, receiver) = oneshot::channel();
+        self.pending.borrow_mut().insert(msg_id, sender);
+
+        self.record(EventKind::Invoke, &node, body.clone());
+        self.cluster.transmit(Message {
+            src: self.client_id.clone(),
+            dest: node.clone(),
+            body,
+        });
+
+        let reply = match time::timeout(self.timeout, receiver).await {
+            Ok(Ok(reply)) => reply,
+            _ => {
+                self.pending.borrow_mut().remove(&msg_id);
+                self.record(EventKind::Info, &node, Value::Null);
+                return Err(Error::Timeout);
+            }
+        };
+
+        match reply.msg_type() {
+            Some(msg_type) if msg_type == Request::Response::TYPE => {
+                self.record(EventKind::Ok, &node, reply.body.clone());
+                Ok(serde_json::from_value(reply.body)?)
+            }
+            Some(msg_type) if msg_type == ErrorBody::TYPE => {
+                let error = Error::from(serde_json::from_value::<ErrorBody>(reply.body.clone())?);
+                let kind = if error.code().is_definite() {
+                    EventKind::Fail
+                } else {
+                    EventKind::Info
+                };
+                self.record(kind, &node, reply.body);
+                Err(error)
+            }
+            msg_type => {
+                self.record(EventKind::Info, &node, reply.body.clone());
+                Err(Error::UnexpectedResponse(
+                    msg_type.unwrap_or("<none>").to_owned(),
+                ))
+            }
+        }
+    }
+
+    fn record(&self, kind: EventKind, node: &NodeId, body: Value) {
+        self.cluster
+            .0
+            .borrow_mut()
+            .record(kind, &self.client_id, node, body);
+    }
+}
+
+/// An entry in the client-visible history, in the style of a Jepsen history:
+/// every `Invoke` is followed by an `Ok`, a definite `Fail`, or an `Info`
+/// when the outcome is unknown.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Event {
+    pub time: Duration,
+    pub client: NodeId,
+    pub node: NodeId,
+    pub kind: EventKind,
+    pub body: Value,
+}
+
+#[derive(Debug, Copy, Clone, Eq, PartialEq)]
+pub enum EventKind {
+    Invoke,
+    Ok,
+    Fail,
+    Info,
+}
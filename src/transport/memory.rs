@@ -0,0 +1,78 @@
+use {
+    super::Transport,
+    crate::{error::Error, protocol::Message},
+    futures::{
+        channel::mpsc::{self, SendError, UnboundedReceiver, UnboundedSender},
+        SinkExt, StreamExt,
+    },
+};
+
+/// An in-process transport, for embedding a node in tests or other hosts. The
+/// paired `MemoryHandle` delivers messages to the node and receives the
+/// messages that it sends.
+#[derive(Debug)]
+pub struct MemoryTransport {
+    incoming: UnboundedReceiver<Message>,
+    outgoing: UnboundedSender<Message>,
+}
+
+#[derive(Debug)]
+pub struct MemoryHandle {
+    incoming: UnboundedSender<Message>,
+    outgoing: UnboundedReceiver<Message>,
+}
+
+impl MemoryTransport {
+    pub fn new() -> (Self, MemoryHandle) {
+        let (incoming_sender, incoming_receiver) = mpsc::unbounded();
+        let (outgoing_sender, outgoing_receiver) = mpsc::unbounded();
+
+        (
+            Self {
+                incoming: incoming_receiver,
+                outgoing: outgoing_sender,
+            },
+            MemoryHandle {
+                incoming: incoming_sender,
+                outgoing: outgoing_receiver,
+            },
+        )
+    }
+}
+
+impl Transport for MemoryTransport {
+    type Incoming = UnboundedReceiver<Message>;
+    type Outgoing = futures::sink::SinkMapErr<UnboundedSender<Message>, fn(SendError) -> Error>;
+
+    fn split(self) -> (Self::Incoming, Self::Outgoing) {
+        (
+            self.incoming,
+            self.outgoing.sink_map_err(|_| Error::TransportClosed),
+        )
+    }
+}
+
+impl MemoryHandle {
+    /// Delivers a message to the node.
+    pub fn send(&self, message: Message) -> Result<(), Error> {
+        self.incoming
+            .unbounded_send(message)
+            .map_err(|_| Error::TransportClosed)
+    }
+
+    /// Waits for the next message sent by the node, returning `None` once the
+    /// node has stopped.
+    pub async fn recv(&mut self) -> Option<Message> {
+        self.outgoing.next().await
+    }
+
+    /// Closes the node's input, as if stdin had been closed.
+    pub fn close(&self) {
+        self.incoming.close_channel();
+    }
+
+    #[cfg_attr(not(feature = "simulation"), allow(dead_code))]
+    pub(crate) fn into_parts(self) -> (UnboundedSender<Message>, UnboundedReceiver<Message>) {
+        (self.incoming, self.outgoing)
+    }
+}
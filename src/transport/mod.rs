@@ -0,0 +1,124 @@
+use {
+    crate::{error::Error, protocol::Message},
+    futures::{ready, Sink, Stream},
+    std::{
+        io::ErrorKind,
+        pin::Pin,
+        task::{Context, Poll},
+    },
+    tokio::{
+        io::{AsyncBufReadExt, AsyncRead, AsyncWrite, BufReader, BufWriter},
+        sync::mpsc,
+    },
+};
+
+mod memory;
+mod stdio;
+mod tcp;
+
+pub use {
+    memory::{MemoryHandle, MemoryTransport},
+    stdio::StdioTransport,
+    tcp::TcpTransport,
+};
+
+/// A source of inbound messages and a sink for outbound messages that a
+/// `Server` can run a `Node` over.
+pub trait Transport {
+    type Incoming: Stream<Item = Message> + Unpin + Send + 'static;
+    type Outgoing: Sink<Message, Error = Error> + Unpin + Send + 'static;
+
+    fn split(self) -> (Self::Incoming, Self::Outgoing);
+}
+
+/// Parses newline delimited JSON messages from `reader` until it closes or
+/// `sender` is dropped, skipping any lines that aren't valid messages.
+async fn read_json_lines(
+    reader: impl AsyncRead + Unpin,
+    mut on_message: impl FnMut(&Message),
+    sender: mpsc::Sender<Message>,
+) {
+    let mut lines = BufReader::new(reader).lines();
+
+    while let Ok(Some(line)) = lines.next_line().await {
+        match serde_json::from_str(&line) {
+            Ok(message) => {
+                on_message(&message);
+                if sender.send(message).await.is_err() {
+                    break;
+                }
+            }
+            Err(err) => eprintln!("error parsing message: {err}"),
+        }
+    }
+}
+
+fn to_json_line(message: &Message) -> Result<String, Error> {
+    let mut json = serde_json::to_string(message)?;
+    json.push('\n');
+    Ok(json)
+}
+
+/// A sink that writes messages as JSON lines through a `BufWriter`, so that
+/// the underlying writer only sees a write when the sink is flushed or the
+/// buffer fills up.
+pub struct JsonLinesSink<W> {
+    writer: BufWriter<W>,
+    line: Vec<u8>,
+    written: usize,
+}
+
+impl<W> JsonLinesSink<W>
+where
+    W: AsyncWrite + Unpin,
+{
+    pub fn new(writer: W) -> Self {
+        Self {
+            writer: BufWriter::new(writer),
+            line: Vec::new(),
+            written: 0,
+        }
+    }
+
+    fn poll_write_line(&mut self, cx: &mut Context<'_>) -> Poll<Result<(), Error>> {
+        while self.written < self.line.len() {
+            let written =
+                ready!(Pin::new(&mut self.writer).poll_write(cx, &self.line[self.written..]))?;
+            if written == 0 {
+                return Poll::Ready(Err(std::io::Error::from(ErrorKind::WriteZero).into()));
+            }
+            self.written += written;
+        }
+
+        Poll::Ready(Ok(()))
+    }
+}
+
+impl<W> Sink<Message> for JsonLinesSink<W>
+where
+    W: AsyncWrite + Unpin,
+{
+    type Error = Error;
+
+    fn poll_ready(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Result<(), Error>> {
+        self.get_mut().poll_write_line(cx)
+    }
+
+    fn start_send(self: Pin<&mut Self>, message: Message) -> Result<(), Error> {
+        let this = self.get_mut();
+        this.line = to_json_line(&message)?.into_bytes();
+        this.written = 0;
+        Ok(())
+    }
+
+    fn poll_flush(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Result<(), Error>> {
+        let this = self.get_mut();
+        ready!(this.poll_write_line(cx))?;
+        Poll::Ready(Ok(ready!(Pin::new(&mut this.writer).poll_flush(cx))?))
+    }
+
+    fn poll_close(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Result<(), Error>> {
+        ready!(self.as_mut().poll_flush(cx))?;
+        Poll::Ready(Ok(ready!(Pin::new(&mut self.writer).poll_shutdown(cx))?))
+    }
+}
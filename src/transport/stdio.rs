@@ -0,0 +1,29 @@
+use {
+    super::{read_json_lines, JsonLinesSink, Transport},
+    crate::protocol::Message,
+    tokio::{
+        io::{stdin, stdout, Stdout},
+        sync::mpsc,
+    },
+    tokio_stream::wrappers::ReceiverStream,
+};
+
+/// Reads messages from stdin and writes them to stdout, one JSON object per
+/// line, as Maelstrom expects.
+#[derive(Debug, Default)]
+pub struct StdioTransport;
+
+impl Transport for StdioTransport {
+    type Incoming = ReceiverStream<Message>;
+    type Outgoing = JsonLinesSink<Stdout>;
+
+    fn split(self) -> (Self::Incoming, Self::Outgoing) {
+        (messages_from_std_in(), JsonLinesSink::new(stdout()))
+    }
+}
+
+fn messages_from_std_in() -> ReceiverStream<Message> {
+    let (sender, receiver) = mpsc::channel(8);
+    tokio::spawn(read_json_lines(stdin(), |_| {}, sender));
+    ReceiverStream::new(receiver)
+}
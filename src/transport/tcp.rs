@@ -0,0 +1,249 @@
+use {
+    super::{read_json_lines, to_json_line, Transport},
+    crate::{
+        error::Error,
+        protocol::{Message, NodeId},
+    },
+    futures::{sink, Sink},
+    std::{
+        collections::HashMap,
+        env,
+        io::ErrorKind,
+        net::SocketAddr,
+        pin::Pin,
+        sync::{
+            atomic::{AtomicU64, Ordering},
+            Arc, Mutex,
+        },
+        time::Duration,
+    },
+    tokio::{
+        io::{AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt},
+        net::{TcpListener, TcpStream},
+        sync::mpsc::{self, error::TrySendError},
+        time::sleep,
+    },
+    tokio_stream::wrappers::ReceiverStream,
+};
+
+const RECONNECT_DELAY: Duration = Duration::from_millis(500);
+/// How many messages can wait for each connection. Past this, messages are
+/// dropped rather than piling up while a peer is down.
+const CONNECTION_BUFFER: usize = 1024;
+
+/// Runs a node as a networked process. Messages are framed as JSON lines,
+/// peers are dialled on demand from a static address map, and anything else
+/// (such as clients) is answered over the connection it last arrived on.
+#[derive(Debug)]
+pub struct TcpTransport {
+    listener: TcpListener,
+    peers: HashMap<NodeId, SocketAddr>,
+}
+
+type Connections = Arc<Mutex<HashMap<NodeId, (u64, mpsc::Sender<Message>)>>>;
+
+impl TcpTransport {
+    pub async fn bind(addr: SocketAddr, peers: HashMap<NodeId, SocketAddr>) -> Result<Self, Error> {
+        Ok(Self {
+            listener: TcpListener::bind(addr).await?,
+            peers,
+        })
+    }
+
+    /// Builds a transport from `GOSSIP_GLOMERS_LISTEN` (an address such as
+    /// `127.0.0.1:7001`) and `GOSSIP_GLOMERS_PEERS` (a comma separated list
+    /// such as `n1=127.0.0.1:7001,n2=127.0.0.1:7002`). Returns `None` when no
+    /// listen address is set, so binaries can fall back to stdio.
+    pub async fn from_env() -> Result<Option<Self>, Error> {
+        let Ok(listen) = env::var("GOSSIP_GLOMERS_LISTEN") else {
+            return Ok(None);
+        };
+
+        let peers = env::var("GOSSIP_GLOMERS_PEERS")
+            .unwrap_or_default()
+            .split(',')
+            .filter(|peer| !peer.is_empty())
+            .map(|peer| match peer.split_once('=') {
+                Some((node_id, addr)) => Ok((NodeId::from(node_id), parse_addr(addr)?)),
+                None => Err(invalid_input(format!("invalid peer: {peer}"))),
+            })
+            .collect::<Result<_, Error>>()?;
+
+        Self::bind(parse_addr(&listen)?, peers).await.map(Some)
+    }
+
+    pub fn local_addr(&self) -> Result<SocketAddr, Error> {
+        Ok(self.listener.local_addr()?)
+    }
+}
+
+impl Transport for TcpTransport {
+    type Incoming = ReceiverStream<Message>;
+    type Outgoing = Pin<Box<dyn Sink<Message, Error = Error> + Send>>;
+
+    fn split(self) -> (Self::Incoming, Self::Outgoing) {
+        let (sender, receiver) = mpsc::channel(8);
+        let connections = Connections::default();
+
+        tokio::spawn(accept(self.listener, sender, Arc::clone(&connections)));
+
+        let outgoing = sink::unfold(
+            (self.peers, HashMap::new(), connections),
+            |(peers, mut dialers, connections), message: Message| async move {
+                route(&peers, &mut dialers, &connections, message);
+                Ok((peers, dialers, connections))
+            },
+        );
+
+        (ReceiverStream::new(receiver), Box::pin(outgoing))
+    }
+}
+
+fn route(
+    peers: &HashMap<NodeId, SocketAddr>,
+    dialers: &mut HashMap<NodeId, mpsc::Sender<Message>>,
+    connections: &Connections,
+    message: Message,
+) {
+    if let Some(&addr) = peers.get(&message.dest) {
+        let dialer = dialers.entry(message.dest.clone()).or_insert_with(|| {
+            let (sender, receiver) = mpsc::channel(CONNECTION_BUFFER);
+            tokio::spawn(dial(addr, receiver));
+            sender
+        });
+        enqueue(dialer, message);
+        return;
+    }
+
+    match connections.lock().unwrap().get(&message.dest) {
+        Some((_, connection)) => enqueue(connection, message),
+        None => eprintln!("no route to {:?}, dropping message", message.dest),
+    }
+}
+
+/// Queues a message for a connection without waiting, dropping it if the
+/// connection is too far behind. Senders already have to tolerate loss.
+fn enqueue(connection: &mpsc::Sender<Message>, message: Message) {
+    if let Err(TrySendError::Full(message)) = connection.try_send(message) {
+        eprintln!("queue to {} is full, dropping message", message.dest);
+    }
+}
+
+async fn accept(listener: TcpListener, sender: mpsc::Sender<Message>, connections: Connections) {
+    static CONNECTION_ID: AtomicU64 = AtomicU64::new(0);
+
+    loop {
+        let stream = match listener.accept().await {
+            Ok((stream, _)) => stream,
+            Err(err) => {
+                eprintln!("error accepting connection: {err}");
+                continue;
+            }
+        };
+
+        let connection_id = CONNECTION_ID.fetch_add(1, Ordering::Relaxed);
+        let (reader, writer) = stream.into_split();
+        let (reply_sender, reply_receiver) = mpsc::channel(CONNECTION_BUFFER);
+        tokio::spawn(write_messages(writer, reply_receiver));
+
+        let sender = sender.clone();
+        let connections = Arc::clone(&connections);
+        tokio::spawn(async move {
+            let on_message = |message: &Message| {
+                connections
+                    .lock()
+                    .unwrap()
+                    .insert(message.src.clone(), (connection_id, reply_sender.clone()));
+            };
+            read_json_lines(reader, on_message, sender).await;
+
+            connections
+                .lock()
+                .unwrap()
+                .retain(|_, (id, _)| *id != connection_id);
+        });
+    }
+}
+
+/// Delivers messages to a peer, reconnecting whenever the peer closes the
+/// connection or a write fails. A message whose write fails is sent again on
+/// the next connection, but delivery is still best effort: one written just
+/// before the peer went away can be lost, like any other dropped message.
+async fn dial(addr: SocketAddr, mut messages: mpsc::Receiver<Message>) {
+    let mut pending = None;
+
+    loop {
+        let stream = match TcpStream::connect(addr).await {
+            Ok(stream) => stream,
+            Err(err) => {
+                eprintln!("error connecting to {addr}: {err}");
+                sleep(RECONNECT_DELAY).await;
+                continue;
+            }
+        };
+        let (mut reader, mut writer) = stream.into_split();
+
+        loop {
+            let message = match pending.take() {
+                Some(message) => message,
+                None => tokio::select! {
+                    biased;
+
+                    result = closed(&mut reader) => {
+                        if let Err(err) = result {
+                            eprintln!("error reading from {addr}: {err}");
+                        }
+                        break;
+                    }
+                    message = messages.recv() => match message {
+                        Some(message) => message,
+                        None => return,
+                    },
+                },
+            };
+
+            if let Err(err) = write_message(&mut writer, &message).await {
+                eprintln!("error writing to {addr}: {err}");
+                pending = Some(message);
+                break;
+            }
+        }
+    }
+}
+
+/// Resolves once the peer has closed the connection. Peers reply over their
+/// own connections, so anything read here is discarded.
+async fn closed(reader: &mut (impl AsyncRead + Unpin)) -> std::io::Result<()> {
+    let mut buffer = [0; 1024];
+    while reader.read(&mut buffer).await? > 0 {}
+    Ok(())
+}
+
+async fn write_messages(
+    mut writer: impl AsyncWrite + Unpin,
+    mut messages: mpsc::Receiver<Message>,
+) {
+    while let Some(message) = messages.recv().await {
+        if let Err(err) = write_message(&mut writer, &message).await {
+            eprintln!("error writing message: {err}");
+            break;
+        }
+    }
+}
+
+async fn write_message(
+    writer: &mut (impl AsyncWrite + Unpin),
+    message: &Message,
+) -> Result<(), Error> {
+    writer.write_all(to_json_line(message)?.as_bytes()).await?;
+    Ok(())
+}
+
+fn parse_addr(addr: &str) -> Result<SocketAddr, Error> {
+    addr.parse()
+        .map_err(|_| invalid_input(format!("invalid address: {addr}")))
+}
+
+fn invalid_input(message: String) -> Error {
+    std::io::Error::new(ErrorKind::InvalidInput, message).into()
+}
@@ -1 +0,0 @@
-mod backoff_policy;
@@ -0,0 +1,57 @@
+use {
+    futures::{future, poll, FutureExt},
+    gossip_glomers::clock::{Elapsed, ManualClock},
+    std::{pin::pin, task::Poll, time::Duration},
+};
+
+#[test]
+fn intervals_skip_the_ticks_they_missed() {
+    let manual = ManualClock::new();
+    let clock = manual.clock();
+    let start = clock.now();
+    let mut interval = clock.interval(Duration::from_millis(10));
+
+    assert_eq!(interval.tick().now_or_never(), Some(start));
+    assert_eq!(interval.tick().now_or_never(), None);
+
+    manual.advance(Duration::from_millis(35));
+    assert_eq!(
+        interval.tick().now_or_never(),
+        Some(start + Duration::from_millis(10))
+    );
+    assert_eq!(
+        interval.tick().now_or_never(),
+        Some(start + Duration::from_millis(35))
+    );
+    assert_eq!(interval.tick().now_or_never(), None);
+
+    manual.advance(Duration::from_millis(10));
+    assert_eq!(
+        interval.tick().now_or_never(),
+        Some(start + Duration::from_millis(45))
+    );
+}
+
+#[tokio::test]
+async fn timeouts_elapse_when_the_clock_is_advanced() {
+    let manual = ManualClock::new();
+    let clock = manual.clock();
+
+    let mut stalled = pin!(clock.timeout(Duration::from_secs(1), future::pending::<()>()));
+    let mut finished = pin!(clock.timeout(
+        Duration::from_secs(1),
+        clock.sleep(Duration::from_millis(500))
+    ));
+    assert_eq!(poll!(&mut stalled), Poll::Pending);
+    assert_eq!(poll!(&mut finished), Poll::Pending);
+
+    manual.advance(Duration::from_millis(500));
+    assert_eq!(poll!(&mut stalled), Poll::Pending);
+    assert_eq!(poll!(&mut finished), Poll::Ready(Ok(())));
+
+    manual.advance(Duration::from_millis(499));
+    assert_eq!(poll!(&mut stalled), Poll::Pending);
+
+    manual.advance(Duration::from_millis(1));
+    assert_eq!(poll!(&mut stalled), Poll::Ready(Err(Elapsed)));
+}
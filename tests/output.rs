@@ -0,0 +1,248 @@
+use {
+    futures::{
+        channel::mpsc::{self, UnboundedReceiver},
+        Sink,
+    },
+    gossip_glomers::{
+        error::{Error, ErrorCode},
+        extract::Json,
+        node::{Node, NodeContext},
+        protocol::{Echo, EchoOk, Message, NodeId},
+        server::Server,
+        transport::{JsonLinesSink, Transport},
+    },
+    serde_json::{json, Value},
+    std::{
+        collections::HashSet,
+        pin::Pin,
+        sync::{Arc, Mutex},
+        task::{Context, Poll},
+    },
+    tokio::io::{duplex, AsyncBufReadExt, BufReader, DuplexStream},
+};
+
+fn message(src: &str, body: Value) -> Message {
+    Message {
+        src: NodeId::from(src),
+        dest: NodeId::from("n1"),
+        body,
+    }
+}
+
+fn init() -> Message {
+    message(
+        "c0",
+        json!({ "type": "init", "msg_id": 1, "node_id": "n1", "node_ids": ["n1"] }),
+    )
+}
+
+#[derive(Debug, Clone, PartialEq)]
+enum Write {
+    Message(Value),
+    Flush,
+    Close,
+}
+
+/// Records what the server does with its output, rather than writing it.
+struct RecordingTransport {
+    incoming: UnboundedReceiver<Message>,
+    writes: Arc<Mutex<Vec<Write>>>,
+}
+
+struct RecordingSink(Arc<Mutex<Vec<Write>>>);
+
+impl Sink<Message> for RecordingSink {
+    type Error = Error;
+
+    fn poll_ready(self: Pin<&mut Self>, _: &mut Context<'_>) -> Poll<Result<(), Error>> {
+        Poll::Ready(Ok(()))
+    }
+
+    fn start_send(self: Pin<&mut Self>, message: Message) -> Result<(), Error> {
+        self.0.lock().unwrap().push(Write::Message(message.body));
+        Ok(())
+    }
+
+    fn poll_flush(self: Pin<&mut Self>, _: &mut Context<'_>) -> Poll<Result<(), Error>> {
+        self.0.lock().unwrap().push(Write::Flush);
+        Poll::Ready(Ok(()))
+    }
+
+    fn poll_close(self: Pin<&mut Self>, _: &mut Context<'_>) -> Poll<Result<(), Error>> {
+        self.0.lock().unwrap().push(Write::Close);
+        Poll::Ready(Ok(()))
+    }
+}
+
+impl Transport for RecordingTransport {
+    type Incoming = UnboundedReceiver<Message>;
+    type Outgoing = RecordingSink;
+
+    fn split(self) -> (Self::Incoming, Self::Outgoing) {
+        (self.incoming, RecordingSink(self.writes))
+    }
+}
+
+/// Writes output as JSON lines into one end of an in-memory pipe.
+struct PipeTransport {
+    incoming: UnboundedReceiver<Message>,
+    output: DuplexStream,
+}
+
+impl Transport for PipeTransport {
+    type Incoming = UnboundedReceiver<Message>;
+    type Outgoing = JsonLinesSink<DuplexStream>;
+
+    fn split(self) -> (Self::Incoming, Self::Outgoing) {
+        (self.incoming, JsonLinesSink::new(self.output))
+    }
+}
+
+async fn burst(context: NodeContext, Json(Echo { echo }): Json<Echo>) -> Result<EchoOk, Error> {
+    for n in 0..echo.as_u64().unwrap() {
+        context
+            .send(NodeId::from("c1"), Echo { echo: n.into() })
+            .await?;
+    }
+    Ok(EchoOk { echo })
+}
+
+#[tokio::test]
+async fn output_is_flushed_in_batches_and_on_shutdown() {
+    let (input, incoming) = mpsc::unbounded();
+    let writes = Arc::<Mutex<Vec<Write>>>::default();
+    input.unbounded_send(init()).unwrap();
+    input
+        .unbounded_send(message(
+            "c1",
+            json!({ "type": "echo", "msg_id": 2, "echo": 10 }),
+        ))
+        .unwrap();
+    input.close_channel();
+
+    let transport = RecordingTransport {
+        incoming,
+        writes: Arc::clone(&writes),
+    };
+    let node = Node::default()
+        .add_handler("echo", burst)
+        .on_shutdown(|context, ()| async move {
+            context
+                .send(NodeId::from("c1"), Echo { echo: "bye".into() })
+                .await
+        });
+    Server::new(transport).serve(node).await.unwrap();
+
+    let writes = writes.lock().unwrap();
+    let burst_start = writes
+        .iter()
+        .position(|write| matches!(write, Write::Message(body) if body["echo"] == 0))
+        .unwrap();
+    assert!(
+        writes[burst_start..burst_start + 11]
+            .iter()
+            .all(|write| matches!(write, Write::Message(_))),
+        "the burst and its reply are written without a flush between them: {writes:?}"
+    );
+
+    let last_message = writes
+        .iter()
+        .rposition(|write| matches!(write, Write::Message(_)))
+        .unwrap();
+    assert_eq!(
+        writes[last_message],
+        Write::Message(json!({ "type": "echo", "echo": "bye" }))
+    );
+    assert_eq!(writes.last(), Some(&Write::Close));
+}
+
+#[tokio::test(flavor = "multi_thread", worker_threads = 4)]
+async fn concurrent_senders_write_whole_lines() {
+    const SENDERS: u64 = 4;
+    const MESSAGES: u64 = 250;
+
+    let (input, incoming) = mpsc::unbounded::<Message>();
+    let (output, reader) = duplex(64);
+    input.unbounded_send(init()).unwrap();
+
+    let node = Node::on_init(|context, _| {
+        for sender in 0..SENDERS {
+            let task_context = context.clone();
+            context.spawn("sender", async move {
+                for n in 0..MESSAGES {
+                    let body = Echo {
+                        echo: json!([sender, n]),
+                    };
+                    let _ = task_context.send(NodeId::from("c1"), body).await;
+                }
+            });
+        }
+        Ok(())
+    });
+
+    let read = async move {
+        let mut lines = BufReader::new(reader).lines();
+        let mut seen = HashSet::new();
+        while let Some(line) = lines.next_line().await.unwrap() {
+            let message: Message = serde_json::from_str(&line).unwrap();
+            if message.msg_type() == Some("echo") {
+                seen.insert(message.body["echo"].to_string());
+            }
+            if seen.len() as u64 == SENDERS * MESSAGES {
+                input.close_channel();
+            }
+        }
+        seen
+    };
+    let transport = PipeTransport { incoming, output };
+    let (served, seen) = tokio::join!(Server::new(transport).serve(node), read);
+
+    served.unwrap();
+    assert_eq!(seen.len() as u64, SENDERS * MESSAGES);
+}
+
+#[tokio::test]
+async fn try_send_fails_instead_of_waiting_when_the_queue_is_full() {
+    async fn flood(context: NodeContext) -> Result<EchoOk, Error> {
+        let mut sent = 0;
+        loop {
+            match context.try_send(NodeId::from("c1"), Echo { echo: sent.into() }) {
+                Ok(()) => sent += 1,
+                Err(error) => {
+                    assert_eq!(error.code(), ErrorCode::TemporarilyUnavailable);
+                    return Ok(EchoOk { echo: sent.into() });
+                }
+            }
+        }
+    }
+
+    let (input, incoming) = mpsc::unbounded();
+    let writes = Arc::<Mutex<Vec<Write>>>::default();
+    input.unbounded_send(init()).unwrap();
+    input
+        .unbounded_send(message(
+            "c1",
+            json!({ "type": "echo", "msg_id": 2, "echo": null }),
+        ))
+        .unwrap();
+    input.close_channel();
+
+    let transport = RecordingTransport {
+        incoming,
+        writes: Arc::clone(&writes),
+    };
+    Server::new(transport)
+        .serve(Node::default().add_handler("echo", flood))
+        .await
+        .unwrap();
+
+    let writes = writes.lock().unwrap();
+    let reply = writes
+        .iter()
+        .find_map(|write| match write {
+            Write::Message(body) if body["type"] == "echo_ok" => Some(body),
+            _ => None,
+        })
+        .unwrap();
+    assert!(reply["echo"].as_u64().unwrap() > 0);
+}
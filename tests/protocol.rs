@@ -0,0 +1,26 @@
+use {
+    gossip_glomers::{
+        error::Error,
+        protocol::{Body, Echo},
+    },
+    serde::{Deserialize, Serialize},
+    serde_json::json,
+};
+
+#[derive(Serialize, Deserialize)]
+struct Counter(u64);
+
+impl Body for Counter {
+    const TYPE: &'static str = "counter";
+}
+
+#[test]
+fn bodies_are_tagged_with_their_type() {
+    let body = Echo { echo: 1.into() }.to_value().unwrap();
+    assert_eq!(body, json!({ "type": "echo", "echo": 1 }));
+}
+
+#[test]
+fn bodies_that_are_not_objects_are_an_error() {
+    assert!(matches!(Counter(1).to_value(), Err(Error::BodyNotAnObject)));
+}
@@ -0,0 +1,153 @@
+use {
+    gossip_glomers::{
+        clock::Clock,
+        error::{Error, ErrorCode},
+        protocol::{Message, NodeId},
+        retry::{is_retryable, ExponentialBackoff, Jitter},
+    },
+    serde_json::json,
+    std::time::Duration,
+    tokio::time::Instant,
+    tower::retry::{budget::Budget, Policy},
+};
+
+const BASE: Duration = Duration::from_millis(10);
+
+fn policy() -> ExponentialBackoff {
+    ExponentialBackoff::new(Clock::system())
+        .base_delay(BASE)
+        .seed(7)
+}
+
+/// Fails every attempt with `error`, returning the delays before each retry
+/// until the policy gives up.
+async fn delays(mut policy: ExponentialBackoff, error: Error) -> Vec<Duration> {
+    let mut delays = Vec::new();
+    while let Some(retry) = Policy::<(), Message, Error>::retry(&policy, &(), Err(&error)) {
+        let start = Instant::now();
+        policy = retry.await;
+        delays.push(start.elapsed());
+    }
+    delays
+}
+
+fn reply(body: serde_json::Value) -> Message {
+    Message {
+        src: NodeId::from("n2"),
+        dest: NodeId::from("n1"),
+        body,
+    }
+}
+
+fn ms(millis: u64) -> Duration {
+    Duration::from_millis(millis)
+}
+
+#[tokio::test(start_paused = true)]
+async fn delays_double_up_to_the_maximum() {
+    let policy = policy().jitter(Jitter::None).max_delay(ms(50)).attempts(5);
+
+    assert_eq!(
+        delays(policy, Error::Timeout).await,
+        [ms(10), ms(20), ms(40), ms(50), ms(50)]
+    );
+}
+
+#[tokio::test(start_paused = true)]
+async fn full_jitter_stays_under_the_exponential_delay() {
+    let policy = || policy().jitter(Jitter::Full).max_delay(ms(80)).attempts(20);
+
+    let first = delays(policy(), Error::Timeout).await;
+    assert_eq!(first.len(), 20);
+    for (attempt, delay) in first.iter().enumerate() {
+        assert!(*delay <= (BASE * 2u32.pow(attempt as u32)).min(ms(80)));
+    }
+    assert_eq!(first, delays(policy(), Error::Timeout).await);
+}
+
+#[tokio::test(start_paused = true)]
+async fn decorrelated_jitter_stays_within_three_times_the_last_delay() {
+    let policy = policy()
+        .jitter(Jitter::Decorrelated)
+        .max_delay(ms(200))
+        .attempts(20);
+
+    let mut previous = BASE;
+    for delay in delays(policy, Error::Timeout).await {
+        assert!(delay >= BASE);
+        assert!(delay <= (previous * 3).min(ms(200)));
+        previous = delay;
+    }
+}
+
+#[tokio::test(start_paused = true)]
+async fn retries_stop_at_the_end_of_the_window() {
+    let policy = policy()
+        .jitter(Jitter::None)
+        .attempts(10)
+        .retry_window(ms(25));
+
+    assert_eq!(delays(policy, Error::Timeout).await, [ms(10)]);
+}
+
+#[tokio::test(start_paused = true)]
+async fn retries_stop_when_the_budget_runs_out() {
+    let policy = policy()
+        .jitter(Jitter::None)
+        .attempts(10)
+        .budget(Budget::new(Duration::from_secs(1), 0, 1.0));
+
+    assert_eq!(delays(policy, Error::Timeout).await, [ms(10)]);
+}
+
+#[tokio::test(start_paused = true)]
+async fn retry_if_decides_what_is_retried() {
+    let policy = || {
+        policy()
+            .attempts(1)
+            .retry_if(|error| error.code() == ErrorCode::KeyDoesNotExist)
+    };
+
+    let missing = Error::new(ErrorCode::KeyDoesNotExist, "no such key");
+    assert_eq!(delays(policy(), missing).await.len(), 1);
+    assert_eq!(delays(policy(), Error::Timeout).await.len(), 0);
+}
+
+#[tokio::test(start_paused = true)]
+async fn error_replies_are_retried_if_they_are_retryable() {
+    let policy = policy().attempts(1);
+    let retries =
+        |body| Policy::<(), Message, Error>::retry(&policy, &(), Ok(&reply(body))).is_some();
+
+    assert!(retries(
+        json!({ "type": "error", "code": 11, "text": "busy" })
+    ));
+    assert!(!retries(
+        json!({ "type": "error", "code": 20, "text": "no such key" })
+    ));
+    assert!(!retries(json!({ "type": "echo_ok", "echo": 1 })));
+}
+
+#[test]
+fn only_errors_that_might_succeed_again_are_retryable() {
+    for code in [
+        ErrorCode::Timeout,
+        ErrorCode::TemporarilyUnavailable,
+        ErrorCode::Crash,
+    ] {
+        assert!(is_retryable(&Error::new(code, "")), "{code:?}");
+    }
+    for code in [
+        ErrorCode::NotSupported,
+        ErrorCode::MalformedRequest,
+        ErrorCode::Abort,
+        ErrorCode::KeyDoesNotExist,
+        ErrorCode::KeyAlreadyExists,
+        ErrorCode::PreconditionFailed,
+        ErrorCode::TxnConflict,
+    ] {
+        assert!(!is_retryable(&Error::new(code, "")), "{code:?}");
+    }
+    assert!(is_retryable(&Error::Timeout));
+    assert!(is_retryable(&Error::TransportClosed));
+}
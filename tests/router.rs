@@ -0,0 +1,55 @@
+use {
+    gossip_glomers::{
+        clock::Clock,
+        error::Error,
+        extract::Json,
+        node::{NodeContext, Router},
+        protocol::{Echo, EchoOk, Init, Message, NodeId},
+    },
+    serde_json::json,
+    std::time::Duration,
+    tokio::sync::mpsc,
+    tower::{Service, ServiceExt},
+};
+
+async fn echo(Json(Echo { echo }): Json<Echo>) -> Result<EchoOk, Error> {
+    Ok(EchoOk { echo })
+}
+
+#[tokio::test]
+async fn routers_can_be_driven_as_services() {
+    let (outgoing, mut sent) = mpsc::channel(8);
+    let init = Init {
+        node_id: NodeId::from("n1"),
+        node_ids: vec![NodeId::from("n1"), NodeId::from("n2")],
+    };
+    let context = NodeContext::new(init, outgoing, Clock::default(), Duration::from_secs(1));
+    let mut service = Router::<()>::new()
+        .route("echo", echo)
+        .into_service(context.clone(), ())
+        .unwrap();
+
+    let request = Message {
+        src: NodeId::from("c1"),
+        dest: NodeId::from("n1"),
+        body: json!({ "type": "echo", "msg_id": 1, "echo": 1 }),
+    };
+    service.ready().await.unwrap().call(request).await.unwrap();
+    let reply = sent.recv().await.unwrap();
+    assert_eq!(reply.body["type"], "echo_ok");
+    assert_eq!(reply.body["in_reply_to"], 1);
+
+    // Replies fed to the service complete the node's own calls.
+    let call = context.call(NodeId::from("n2"), Echo { echo: 2.into() });
+    let peer = async {
+        let request = sent.recv().await.unwrap();
+        let reply = Message {
+            src: NodeId::from("n2"),
+            dest: NodeId::from("n1"),
+            body: json!({ "type": "echo_ok", "in_reply_to": request.body["msg_id"], "echo": 2 }),
+        };
+        service.ready().await.unwrap().call(reply).await.unwrap();
+    };
+    let (response, ()) = tokio::join!(call, peer);
+    assert_eq!(response.unwrap().echo, 2);
+}
@@ -0,0 +1,544 @@
+use {
+    gossip_glomers::{
+        clock::ManualClock,
+        error::{Error, ErrorCode},
+        extract::{Json, RawMessage, State},
+        node::{MessageService, Node, NodeContext},
+        protocol::{Echo, EchoOk, Message, NodeId},
+        server::Server,
+        transport::MemoryTransport,
+    },
+    serde_json::{json, Value},
+    std::{
+        sync::{
+            atomic::{AtomicBool, AtomicUsize, Ordering},
+            Arc, Mutex,
+        },
+        time::Duration,
+    },
+    tokio::time::sleep,
+    tower::{layer::layer_fn, service_fn, timeout::TimeoutLayer},
+};
+
+fn message(src: &str, body: Value) -> Message {
+    Message {
+        src: NodeId::from(src),
+        dest: NodeId::from("n1"),
+        body,
+    }
+}
+
+fn init(node_id: &str) -> Message {
+    message(
+        "c0",
+        json!({ "type": "init", "msg_id": 1, "node_id": node_id, "node_ids": [node_id] }),
+    )
+}
+
+/// Feeds `input` to a node, closes its input, and returns what it sent back.
+async fn serve<State>(node: Node<State>, input: Vec<Message>) -> (Result<(), Error>, Vec<Message>)
+where
+    State: Clone + 'static,
+{
+    serve_with(|server| server, node, input).await
+}
+
+async fn serve_with<State>(
+    configure: impl FnOnce(Server<MemoryTransport>) -> Server<MemoryTransport>,
+    node: Node<State>,
+    input: Vec<Message>,
+) -> (Result<(), Error>, Vec<Message>)
+where
+    State: Clone + 'static,
+{
+    let (transport, mut handle) = MemoryTransport::new();
+    for message in input {
+        handle.send(message).unwrap();
+    }
+    handle.close();
+
+    let result = configure(Server::new(transport)).serve(node).await;
+
+    let mut output = Vec::new();
+    while let Some(message) = handle.recv().await {
+        output.push(message);
+    }
+    (result, output)
+}
+
+fn reply_to(output: &[Message], msg_id: u64) -> &Value {
+    &output
+        .iter()
+        .find(|message| message.body["in_reply_to"] == msg_id)
+        .unwrap_or_else(|| panic!("no reply to {msg_id} in {output:?}"))
+        .body
+}
+
+async fn echo(Json(Echo { echo }): Json<Echo>) -> Result<EchoOk, Error> {
+    Ok(EchoOk { echo })
+}
+
+#[tokio::test]
+async fn failing_handlers_reply_with_their_error_code() {
+    async fn missing(Json(_): Json<Echo>) -> Result<EchoOk, Error> {
+        Err(Error::new(ErrorCode::KeyDoesNotExist, "no such key"))
+    }
+
+    let node = Node::default().add_handler("echo", missing);
+    let (_, output) = serve(
+        node,
+        vec![
+            init("n1"),
+            message("c1", json!({ "type": "echo", "msg_id": 2, "echo": 1 })),
+        ],
+    )
+    .await;
+
+    let reply = reply_to(&output, 2);
+    assert_eq!(reply["type"], "error");
+    assert_eq!(reply["code"], 20);
+    assert_eq!(reply["text"], "no such key");
+}
+
+#[tokio::test]
+async fn malformed_bodies_are_rejected() {
+    let node = Node::default().add_handler("echo", echo);
+    let (_, output) = serve(
+        node,
+        vec![
+            init("n1"),
+            message("c1", json!({ "type": "echo", "msg_id": 2 })),
+        ],
+    )
+    .await;
+
+    let reply = reply_to(&output, 2);
+    assert_eq!(reply["type"], "error");
+    assert_eq!(reply["code"], 12);
+}
+
+#[tokio::test]
+async fn failing_to_parse_inside_a_handler_is_indefinite() {
+    async fn parse(Json(Echo { echo }): Json<Echo>) -> Result<EchoOk, Error> {
+        Ok(serde_json::from_value(echo)?)
+    }
+
+    let node = Node::default().add_handler("echo", parse);
+    let (_, output) = serve(
+        node,
+        vec![
+            init("n1"),
+            message("c1", json!({ "type": "echo", "msg_id": 2, "echo": 1 })),
+        ],
+    )
+    .await;
+
+    let reply = reply_to(&output, 2);
+    assert_eq!(reply["type"], "error");
+    assert_eq!(reply["code"], 13);
+}
+
+#[tokio::test]
+async fn unknown_types_are_not_supported() {
+    let (_, output) = serve(
+        Node::default(),
+        vec![
+            init("n1"),
+            message("c1", json!({ "type": "cas", "msg_id": 2 })),
+        ],
+    )
+    .await;
+
+    let reply = reply_to(&output, 2);
+    assert_eq!(reply["type"], "error");
+    assert_eq!(reply["code"], 10);
+}
+
+#[tokio::test]
+async fn the_fallback_receives_unrouted_messages() {
+    async fn record(
+        State(seen): State<Arc<Mutex<Vec<String>>>>,
+        RawMessage(message): RawMessage,
+    ) -> Result<(), Error> {
+        seen.lock()
+            .unwrap()
+            .push(message.msg_type().unwrap_or_default().to_owned());
+        Ok(())
+    }
+
+    let seen = Arc::<Mutex<Vec<String>>>::default();
+    let node = Node::with_state(Arc::clone(&seen)).fallback(record);
+    let (_, output) = serve(
+        node,
+        vec![
+            init("n1"),
+            message("c1", json!({ "type": "cas", "msg_id": 2 })),
+        ],
+    )
+    .await;
+
+    assert_eq!(*seen.lock().unwrap(), vec!["cas".to_owned()]);
+    assert!(output
+        .iter()
+        .all(|message| message.body["in_reply_to"] != 2));
+}
+
+#[tokio::test]
+async fn messages_before_init_are_handled_after_it() {
+    let node = Node::default().add_handler("echo", echo);
+    let (result, output) = serve(
+        node,
+        vec![
+            message(
+                "c1",
+                json!({ "type": "echo", "msg_id": 2, "echo": "early" }),
+            ),
+            init("n1"),
+        ],
+    )
+    .await;
+
+    assert!(result.is_ok());
+    assert_eq!(output[0].body["type"], "init_ok");
+    assert_eq!(reply_to(&output, 2)["echo"], "early");
+}
+
+#[tokio::test]
+async fn a_repeated_init_is_acknowledged_again() {
+    let mut again = init("n1");
+    again.body["msg_id"] = 2.into();
+
+    let (_, output) = serve(Node::default(), vec![init("n1"), again]).await;
+
+    assert_eq!(reply_to(&output, 1)["type"], "init_ok");
+    assert_eq!(reply_to(&output, 2)["type"], "init_ok");
+}
+
+#[tokio::test]
+async fn an_init_with_another_node_id_is_refused() {
+    let mut other = init("n2");
+    other.body["msg_id"] = 2.into();
+
+    let (_, output) = serve(Node::default(), vec![init("n1"), other]).await;
+
+    let reply = reply_to(&output, 2);
+    assert_eq!(reply["type"], "error");
+    assert_eq!(reply["code"], 22);
+}
+
+#[tokio::test]
+async fn closing_before_init_is_an_error() {
+    let (result, output) = serve(
+        Node::default(),
+        vec![message(
+            "c1",
+            json!({ "type": "echo", "msg_id": 2, "echo": 1 }),
+        )],
+    )
+    .await;
+
+    assert!(matches!(result, Err(Error::ClosedBeforeInit)));
+    assert!(output.is_empty());
+}
+
+#[tokio::test(start_paused = true)]
+async fn shutdown_waits_for_handlers_within_the_grace_period() {
+    async fn slow(Json(Echo { echo }): Json<Echo>) -> Result<EchoOk, Error> {
+        let delay = echo.as_u64().unwrap();
+        sleep(Duration::from_secs(delay)).await;
+        Ok(EchoOk { echo })
+    }
+
+    let node = Node::default().add_handler("echo", slow);
+    let (result, output) = serve_with(
+        |server| server.grace_period(Duration::from_secs(5)),
+        node,
+        vec![
+            init("n1"),
+            message("c1", json!({ "type": "echo", "msg_id": 2, "echo": 1 })),
+            message("c1", json!({ "type": "echo", "msg_id": 3, "echo": 60 })),
+        ],
+    )
+    .await;
+
+    assert!(result.is_ok());
+    assert_eq!(reply_to(&output, 2)["echo"], 1);
+    assert!(output
+        .iter()
+        .all(|message| message.body["in_reply_to"] != 3));
+}
+
+#[tokio::test(start_paused = true)]
+async fn shutdown_cancels_tasks_and_then_runs_the_hook() {
+    struct SetOnDrop(Arc<AtomicBool>);
+
+    impl Drop for SetOnDrop {
+        fn drop(&mut self) {
+            self.0.store(true, Ordering::SeqCst);
+        }
+    }
+
+    let cancelled = Arc::new(AtomicBool::new(false));
+    let task_flag = Arc::clone(&cancelled);
+    let hook_flag = Arc::clone(&cancelled);
+
+    let node = Node::on_init(move |context, _| {
+        let guard = SetOnDrop(task_flag);
+        context.spawn("forever", async move {
+            let _guard = guard;
+            std::future::pending::<()>().await
+        });
+        Ok(())
+    })
+    .on_shutdown(move |context, ()| async move {
+        let cancelled = hook_flag.load(Ordering::SeqCst);
+        context
+            .send(
+                NodeId::from("c0"),
+                Echo {
+                    echo: cancelled.into(),
+                },
+            )
+            .await
+    });
+    let (result, output) = serve(node, vec![init("n1")]).await;
+
+    assert!(result.is_ok());
+    assert!(cancelled.load(Ordering::SeqCst));
+    let last = output.last().unwrap();
+    assert_eq!(last.body["type"], "echo");
+    assert_eq!(
+        last.body["echo"], true,
+        "tasks are cancelled before the hook runs"
+    );
+}
+
+#[tokio::test]
+async fn server_nodes_are_ordered_by_number() {
+    async fn list(context: NodeContext) -> Result<EchoOk, Error> {
+        let server_nodes = context.server_nodes();
+        Ok(EchoOk {
+            echo: json!(server_nodes),
+        })
+    }
+
+    let node_ids = ["n10", "c1", "n2", "n1"];
+    let node = Node::default().add_handler("echo", list);
+    let (_, output) = serve(
+        node,
+        vec![
+            message(
+                "c0",
+                json!({ "type": "init", "msg_id": 1, "node_id": "n1", "node_ids": node_ids }),
+            ),
+            message("c1", json!({ "type": "echo", "msg_id": 2, "echo": null })),
+        ],
+    )
+    .await;
+
+    assert_eq!(reply_to(&output, 2)["echo"], json!(["n1", "n2", "n10"]));
+}
+
+#[tokio::test(start_paused = true)]
+async fn route_layers_can_come_before_their_route() {
+    async fn stall() -> Result<EchoOk, Error> {
+        std::future::pending().await
+    }
+
+    let node = Node::default()
+        .route_layer("echo", TimeoutLayer::new(Duration::from_millis(100)))
+        .add_handler("echo", stall);
+    let (_, output) = serve(
+        node,
+        vec![
+            init("n1"),
+            message("c1", json!({ "type": "echo", "msg_id": 2, "echo": 1 })),
+        ],
+    )
+    .await;
+
+    let reply = reply_to(&output, 2);
+    assert_eq!(reply["type"], "error");
+    assert_eq!(reply["code"], 0);
+}
+
+#[tokio::test]
+async fn route_layers_without_a_route_are_an_error() {
+    let node = Node::default().route_layer("echo", TimeoutLayer::new(Duration::from_millis(100)));
+    let (result, _) = serve(node, vec![init("n1")]).await;
+
+    assert!(matches!(result, Err(Error::MissingRoute(msg_type)) if msg_type == "echo"));
+}
+
+#[tokio::test]
+async fn layers_that_reject_with_an_error_reply_with_its_code() {
+    let reject = layer_fn(|_: MessageService| {
+        service_fn(|_: Message| async { Err::<(), _>(Error::new(ErrorCode::Abort, "rejected")) })
+    });
+
+    let node = Node::default()
+        .add_handler("echo", echo)
+        .route_layer("echo", reject);
+    let (_, output) = serve(
+        node,
+        vec![
+            init("n1"),
+            message("c1", json!({ "type": "echo", "msg_id": 2, "echo": 1 })),
+        ],
+    )
+    .await;
+
+    let reply = reply_to(&output, 2);
+    assert_eq!(reply["type"], "error");
+    assert_eq!(reply["code"], 14);
+    assert_eq!(reply["text"], "rejected");
+    assert_eq!(
+        output
+            .iter()
+            .filter(|message| message.body["in_reply_to"] == 2)
+            .count(),
+        1
+    );
+}
+
+#[tokio::test]
+async fn on_init_failures_are_replied_to_init() {
+    let node = Node::<()>::on_init(|_, _| Err(Error::new(ErrorCode::Abort, "no disk")));
+    let (result, output) = serve(node, vec![init("n1")]).await;
+
+    assert!(matches!(
+        result,
+        Err(Error::Maelstrom {
+            code: ErrorCode::Abort,
+            ..
+        })
+    ));
+    let reply = reply_to(&output, 1);
+    assert_eq!(reply["type"], "error");
+    assert_eq!(reply["code"], 14);
+    assert_eq!(reply["text"], "no disk");
+}
+
+#[tokio::test]
+async fn on_init_sees_the_node_and_its_peers() {
+    async fn describe(State(description): State<Value>) -> Result<EchoOk, Error> {
+        Ok(EchoOk { echo: description })
+    }
+
+    let node = Node::on_init(|context, init| {
+        let peers: Vec<NodeId> = context.peers().cloned().collect();
+        Ok(json!({ "node_id": init.node_id, "peers": peers }))
+    })
+    .add_handler("echo", describe);
+    let (_, output) = serve(
+        node,
+        vec![
+            message(
+                "c0",
+                json!({ "type": "init", "msg_id": 1, "node_id": "n2", "node_ids": ["n1", "n2", "n3"] }),
+            ),
+            message("c1", json!({ "type": "echo", "msg_id": 2, "echo": null })),
+        ],
+    )
+    .await;
+
+    assert_eq!(
+        reply_to(&output, 2)["echo"],
+        json!({ "node_id": "n2", "peers": ["n1", "n3"] })
+    );
+}
+
+/// Lets every task that can make progress do so.
+async fn settle() {
+    sleep(Duration::from_millis(1)).await;
+}
+
+#[tokio::test(start_paused = true)]
+async fn periodic_tasks_tick_on_the_node_clock_from_init_to_shutdown() {
+    let manual = ManualClock::new();
+    let ticks = Arc::new(AtomicUsize::new(0));
+    let node = Node::with_state(Arc::clone(&ticks))
+        .clock(manual.clock())
+        .add_periodic_task("tick", Duration::from_millis(10), |_, ticks| async move {
+            ticks.fetch_add(1, Ordering::SeqCst);
+        });
+
+    let (transport, handle) = MemoryTransport::new();
+    let drive = async {
+        settle().await;
+        assert_eq!(ticks.load(Ordering::SeqCst), 0, "no ticks before init");
+
+        handle.send(init("n1")).unwrap();
+        settle().await;
+        assert_eq!(
+            ticks.load(Ordering::SeqCst),
+            1,
+            "the first tick is immediate"
+        );
+
+        sleep(Duration::from_millis(50)).await;
+        assert_eq!(
+            ticks.load(Ordering::SeqCst),
+            1,
+            "only the node's clock counts"
+        );
+        manual.advance(Duration::from_millis(10));
+        settle().await;
+        assert_eq!(ticks.load(Ordering::SeqCst), 2);
+
+        handle.close();
+    };
+    let (result, ()) = tokio::join!(Server::new(transport).serve(node), drive);
+
+    assert!(result.is_ok());
+    manual.advance(Duration::from_millis(100));
+    settle().await;
+    assert_eq!(ticks.load(Ordering::SeqCst), 2, "tasks stop at shutdown");
+}
+
+#[tokio::test(start_paused = true)]
+async fn periodic_tasks_only_restart_after_a_panic_if_asked_to() {
+    async fn ticks_after_panicking(restart_on_panic: bool) -> usize {
+        let manual = ManualClock::new();
+        let ticks = Arc::new(AtomicUsize::new(0));
+        let node = Node::with_state(Arc::clone(&ticks))
+            .clock(manual.clock())
+            .restart_on_panic(restart_on_panic)
+            .add_periodic_task("panic", Duration::from_millis(10), |_, ticks| async move {
+                ticks.fetch_add(1, Ordering::SeqCst);
+                panic!("tick");
+            });
+
+        let (transport, handle) = MemoryTransport::new();
+        let drive = async {
+            handle.send(init("n1")).unwrap();
+            for _ in 0..3 {
+                settle().await;
+                manual.advance(Duration::from_millis(10));
+            }
+            settle().await;
+            handle.close();
+        };
+        let (result, ()) = tokio::join!(Server::new(transport).serve(node), drive);
+        assert!(result.is_ok());
+
+        ticks.load(Ordering::SeqCst)
+    }
+
+    assert_eq!(ticks_after_panicking(true).await, 4);
+    assert_eq!(ticks_after_panicking(false).await, 1);
+}
+
+#[tokio::test]
+async fn malformed_inits_are_refused() {
+    let malformed = |msg_id: u64| message("c0", json!({ "type": "init", "msg_id": msg_id }));
+    let mut valid = init("n1");
+    valid.body["msg_id"] = 2.into();
+
+    let (result, output) = serve(Node::default(), vec![malformed(1), valid, malformed(3)]).await;
+
+    assert!(result.is_ok());
+    assert_eq!(reply_to(&output, 1)["code"], 12);
+    assert_eq!(reply_to(&output, 2)["type"], "init_ok");
+    assert_eq!(reply_to(&output, 3)["code"], 12);
+}
@@ -0,0 +1,431 @@
+use {
+    futures::future,
+    gossip_glomers::{
+        error::{Error, ErrorCode},
+        extract::{Json, Responder, State},
+        node::{Node, NodeContext, Router},
+        protocol::{Broadcast, BroadcastOk, Echo, EchoOk, NodeId, Read, ReadOk},
+        retry::ExponentialBackoff,
+        server::Server,
+        simulation::{Event, EventKind, Simulation},
+        transport::MemoryTransport,
+    },
+    serde_json::{json, Value},
+    std::{
+        sync::{Arc, Mutex},
+        time::Duration,
+    },
+    tower::{retry::Retry, timeout::TimeoutLayer, Service, ServiceExt},
+};
+
+async fn echo(Json(Echo { echo }): Json<Echo>) -> Result<EchoOk, Error> {
+    Ok(EchoOk { echo })
+}
+
+fn echo_cluster(seed: u64) -> Simulation {
+    Simulation::new(seed)
+        .node("n1", Node::default().add_handler("echo", echo))
+        .node("n2", Node::default().add_handler("echo", echo))
+        .latency(Duration::from_millis(1)..Duration::from_millis(50))
+        .loss(0.2)
+        .duplication(0.2)
+}
+
+fn run_echoes(seed: u64) -> Vec<Event> {
+    echo_cluster(seed).run(|cluster| async move {
+        let client = cluster.client("c1");
+        for i in 0..20 {
+            let _ = client
+                .call(
+                    if i % 2 == 0 { "n1" } else { "n2" },
+                    Echo { echo: i.into() },
+                )
+                .await;
+        }
+        cluster.history()
+    })
+}
+
+#[test]
+fn same_seed_reproduces_the_same_history() {
+    assert_eq!(run_echoes(7), run_echoes(7));
+}
+
+#[test]
+fn every_invoke_completes() {
+    let history = run_echoes(42);
+
+    let invokes = history
+        .iter()
+        .filter(|event| event.kind == EventKind::Invoke)
+        .count();
+    assert_eq!(invokes, 20);
+    assert_eq!(history.len(), 40);
+    assert!(history.iter().any(|event| event.kind == EventKind::Ok));
+    assert!(history.iter().any(|event| event.kind == EventKind::Info));
+}
+
+#[test]
+fn partitioned_requests_time_out() {
+    Simulation::new(1)
+        .node("n1", Node::default().add_handler("echo", echo))
+        .run(|cluster| async move {
+            let client = cluster.client("c1");
+            cluster.partition(["c1"], ["n1"]);
+
+            let error = client
+                .call("n1", Echo { echo: Value::Null })
+                .await
+                .unwrap_err();
+            assert_eq!(error.code(), ErrorCode::Timeout);
+
+            cluster.heal();
+            let EchoOk { echo } = client.call("n1", Echo { echo: 3.into() }).await.unwrap();
+            assert_eq!(echo, 3);
+        });
+}
+
+#[test]
+fn route_layers_reply_when_they_fail() {
+    async fn stall() -> Result<EchoOk, Error> {
+        future::pending().await
+    }
+
+    let node = Node::default()
+        .add_handler("echo", stall)
+        .route_layer("echo", TimeoutLayer::new(Duration::from_millis(100)));
+
+    Simulation::new(1)
+        .node("n1", node)
+        .run(|cluster| async move {
+            let error = cluster
+                .client("c1")
+                .call("n1", Echo { echo: Value::Null })
+                .await
+                .unwrap_err();
+            assert_eq!(error.code(), ErrorCode::Timeout);
+            assert!(cluster.elapsed() < Duration::from_secs(1));
+        });
+}
+
+#[test]
+fn nested_routers_are_served_and_cannot_overlap() {
+    let echoes = || Router::<()>::new().route("echo", echo);
+
+    let node = Node::default().nest(echoes()).unwrap();
+    assert!(matches!(
+        Node::default().add_handler("echo", echo).nest(echoes()),
+        Err(Error::DuplicateRoute(msg_type)) if msg_type == "echo"
+    ));
+
+    // Adding a handler after nesting is caught too, when the node is served.
+    let (transport, _handle) = MemoryTransport::new();
+    let served = tokio::runtime::Builder::new_current_thread()
+        .enable_all()
+        .build()
+        .unwrap()
+        .block_on(
+            Server::new(transport).serve(
+                Node::default()
+                    .nest(echoes())
+                    .unwrap()
+                    .add_handler("echo", echo),
+            ),
+        );
+    assert!(matches!(
+        served,
+        Err(Error::DuplicateRoute(msg_type)) if msg_type == "echo"
+    ));
+
+    Simulation::new(1)
+        .node("n1", node)
+        .run(|cluster| async move {
+            let EchoOk { echo } = cluster
+                .client("c1")
+                .call("n1", Echo { echo: 5.into() })
+                .await
+                .unwrap();
+            assert_eq!(echo, 5);
+        });
+}
+
+#[test]
+fn responders_reply_later_from_another_task() {
+    async fn deferred_echo(
+        context: NodeContext,
+        responder: Responder,
+        Json(Echo { echo }): Json<Echo>,
+    ) -> Result<(), Error> {
+        let clock = context.clock().clone();
+        context.spawn("deferred reply", async move {
+            clock.sleep(Duration::from_millis(200)).await;
+            let _ = responder.reply(EchoOk { echo }).await;
+        });
+        Ok(())
+    }
+
+    Simulation::new(1)
+        .node("n1", Node::default().add_handler("echo", deferred_echo))
+        .run(|cluster| async move {
+            let EchoOk { echo } = cluster
+                .client("c1")
+                .call("n1", Echo { echo: 8.into() })
+                .await
+                .unwrap();
+            assert_eq!(echo, 8);
+            assert!(cluster.elapsed() >= Duration::from_millis(200));
+        });
+}
+
+#[test]
+fn sends_expect_no_reply() {
+    async fn relay(context: NodeContext, Json(Echo { echo }): Json<Echo>) -> Result<EchoOk, Error> {
+        let peers: Vec<NodeId> = context.peers().cloned().collect();
+        context
+            .send_all(
+                peers,
+                Broadcast {
+                    message: echo.clone(),
+                },
+            )
+            .await?;
+        Ok(EchoOk { echo })
+    }
+
+    async fn record(
+        State(received): State<Arc<Mutex<Vec<Value>>>>,
+        Json(Broadcast { message }): Json<Broadcast>,
+    ) -> Result<BroadcastOk, Error> {
+        received.lock().unwrap().push(message);
+        Ok(BroadcastOk {})
+    }
+
+    let received = Arc::<Mutex<Vec<Value>>>::default();
+    let receiver = Node::with_state(Arc::clone(&received)).add_handler("broadcast", record);
+
+    Simulation::new(1)
+        .node("n1", Node::default().add_handler("echo", relay))
+        .node("n2", receiver)
+        .run(|cluster| async move {
+            cluster
+                .client("c1")
+                .call("n1", Echo { echo: 4.into() })
+                .await
+                .unwrap();
+            tokio::time::sleep(Duration::from_millis(100)).await;
+            assert_eq!(*received.lock().unwrap(), vec![Value::from(4)]);
+        });
+}
+
+#[test]
+fn calls_to_partitioned_peers_time_out() {
+    async fn forward(context: NodeContext, Json(request): Json<Echo>) -> Result<EchoOk, Error> {
+        context.call(NodeId::from("n2"), request).await
+    }
+
+    Simulation::new(1)
+        .node(
+            "n1",
+            Node::default()
+                .rpc_timeout(Duration::from_millis(100))
+                .add_handler("echo", forward),
+        )
+        .node("n2", Node::default().add_handler("echo", echo))
+        .run(|cluster| async move {
+            let client = cluster.client("c1");
+
+            let EchoOk { echo } = client.call("n1", Echo { echo: 1.into() }).await.unwrap();
+            assert_eq!(echo, 1);
+
+            cluster.partition(["n1"], ["n2"]);
+            let error = client
+                .call("n1", Echo { echo: 2.into() })
+                .await
+                .unwrap_err();
+            assert_eq!(error.code(), ErrorCode::Timeout);
+            assert!(cluster.elapsed() < Duration::from_secs(1));
+        });
+}
+
+#[test]
+fn one_service_carries_concurrent_calls() {
+    async fn fan_out(context: NodeContext) -> Result<ReadOk, Error> {
+        let mut service = context.send_to(NodeId::from("n2"));
+        let (a, b) = future::join(
+            service.call(Echo { echo: 1.into() }),
+            service.call(Echo { echo: 2.into() }),
+        )
+        .await;
+
+        let echoes = [a?, b?].map(|reply| reply.body["echo"].clone());
+        Ok(ReadOk {
+            messages: echoes.to_vec().into(),
+        })
+    }
+
+    Simulation::new(1)
+        .node("n1", Node::default().add_handler("read", fan_out))
+        .node("n2", Node::default().add_handler("echo", echo))
+        .run(|cluster| async move {
+            let ReadOk { messages } = cluster.client("c1").call("n1", Read {}).await.unwrap();
+            assert_eq!(messages, Value::from(vec![1, 2]));
+        });
+}
+
+#[test]
+fn quorum_calls_need_enough_replies() {
+    async fn count(context: NodeContext, Json(Echo { echo }): Json<Echo>) -> Result<EchoOk, Error> {
+        let quorum = echo.as_u64().unwrap() as usize;
+        let peers: Vec<NodeId> = context.peers().cloned().collect();
+        let replies = context
+            .quorum_call(
+                peers,
+                Echo { echo: Value::Null },
+                quorum,
+                Duration::from_millis(100),
+            )
+            .await?;
+        Ok(EchoOk {
+            echo: replies.len().into(),
+        })
+    }
+
+    Simulation::new(1)
+        .node("n1", Node::default().add_handler("echo", count))
+        .node("n2", Node::default().add_handler("echo", echo))
+        .node("n3", Node::default().add_handler("echo", echo))
+        .run(|cluster| async move {
+            let client = cluster.client("c1");
+            cluster.partition(["n1"], ["n3"]);
+
+            let EchoOk { echo } = client.call("n1", Echo { echo: 1.into() }).await.unwrap();
+            assert_eq!(echo, 1);
+
+            let error = client
+                .call("n1", Echo { echo: 2.into() })
+                .await
+                .unwrap_err();
+            assert_eq!(error.code(), ErrorCode::Timeout);
+            assert!(cluster.elapsed() < Duration::from_secs(1));
+        });
+}
+
+#[test]
+fn first_success_reports_why_every_call_failed() {
+    async fn find(context: NodeContext, Json(request): Json<Echo>) -> Result<EchoOk, Error> {
+        let peers: Vec<NodeId> = context.peers().cloned().collect();
+        let (node, EchoOk { echo }) = context
+            .first_success(peers, request, Duration::from_millis(100))
+            .await?;
+        Ok(EchoOk {
+            echo: json!([node, echo]),
+        })
+    }
+
+    async fn missing() -> Result<EchoOk, Error> {
+        Err(Error::new(ErrorCode::KeyDoesNotExist, "no such key"))
+    }
+
+    async fn stall() -> Result<EchoOk, Error> {
+        future::pending().await
+    }
+
+    let cluster = |n3: Node<()>| {
+        Simulation::new(1)
+            .node("n1", Node::default().add_handler("echo", find))
+            .node("n2", Node::default().add_handler("echo", missing))
+            .node("n3", n3)
+    };
+
+    cluster(Node::default().add_handler("echo", echo)).run(|cluster| async move {
+        let EchoOk { echo } = cluster
+            .client("c1")
+            .call("n1", Echo { echo: 4.into() })
+            .await
+            .unwrap();
+        assert_eq!(echo, json!(["n3", 4]));
+    });
+
+    cluster(Node::default().add_handler("echo", missing)).run(|cluster| async move {
+        let error = cluster
+            .client("c1")
+            .call("n1", Echo { echo: 4.into() })
+            .await
+            .unwrap_err();
+        assert_eq!(error.code(), ErrorCode::KeyDoesNotExist);
+    });
+
+    Simulation::new(1)
+        .node("n1", Node::default().add_handler("echo", find))
+        .run(|cluster| async move {
+            let error = cluster
+                .client("c1")
+                .call("n1", Echo { echo: 4.into() })
+                .await
+                .unwrap_err();
+            assert_eq!(error.code(), ErrorCode::MalformedRequest);
+        });
+
+    cluster(Node::default().add_handler("echo", stall)).run(|cluster| async move {
+        let error = cluster
+            .client("c1")
+            .call("n1", Echo { echo: 4.into() })
+            .await
+            .unwrap_err();
+        assert_eq!(error.code(), ErrorCode::Timeout);
+    });
+}
+
+#[test]
+fn retries_outlast_a_short_partition() {
+    async fn forward(context: NodeContext, Json(request): Json<Echo>) -> Result<EchoOk, Error> {
+        let policy = ExponentialBackoff::new(context.clock().clone())
+            .attempts(5)
+            .base_delay(Duration::from_millis(50))
+            .seed(1);
+        let service = Retry::new(policy, context.send_to(NodeId::from("n2")));
+
+        let reply = service.oneshot(request).await?;
+        Ok(serde_json::from_value(reply.body)?)
+    }
+
+    Simulation::new(1)
+        .node(
+            "n1",
+            Node::default()
+                .rpc_timeout(Duration::from_millis(100))
+                .add_handler("echo", forward),
+        )
+        .node("n2", Node::default().add_handler("echo", echo))
+        .run(|cluster| async move {
+            cluster.partition(["n1"], ["n2"]);
+            tokio::task::spawn_local({
+                let cluster = cluster.clone();
+                async move {
+                    tokio::time::sleep(Duration::from_millis(150)).await;
+                    cluster.heal();
+                }
+            });
+
+            let EchoOk { echo } = cluster
+                .client("c1")
+                .call("n1", Echo { echo: 6.into() })
+                .await
+                .unwrap();
+            assert_eq!(echo, 6);
+        });
+}
+
+#[test]
+fn failing_to_extract_after_a_responder_still_replies() {
+    async fn deferred(_: Responder, Json(_): Json<Echo>) -> Result<(), Error> {
+        Ok(())
+    }
+
+    Simulation::new(1)
+        .node("n1", Node::default().add_handler("read", deferred))
+        .run(|cluster| async move {
+            let error = cluster.client("c1").call("n1", Read {}).await.unwrap_err();
+            assert_eq!(error.code(), ErrorCode::MalformedRequest);
+        });
+}
@@ -0,0 +1,141 @@
+use {
+    futures::{SinkExt, StreamExt},
+    gossip_glomers::{
+        protocol::{Message, NodeId},
+        transport::{TcpTransport, Transport},
+    },
+    serde_json::json,
+    std::{collections::HashMap, net::SocketAddr, thread, time::Duration},
+    tokio::{
+        runtime,
+        sync::{mpsc, oneshot},
+        time::{sleep, timeout},
+    },
+};
+
+/// A peer listening on `addr`, on a runtime of its own so that stopping it
+/// closes every one of its connections, as if its process had exited.
+struct Peer {
+    received: mpsc::UnboundedReceiver<Message>,
+    stop: oneshot::Sender<()>,
+    thread: thread::JoinHandle<()>,
+}
+
+impl Peer {
+    fn start(addr: SocketAddr) -> Self {
+        let (forward, received) = mpsc::unbounded_channel();
+        let (stop, stopped) = oneshot::channel();
+        let (listening, is_listening) = std::sync::mpsc::channel();
+
+        let thread = thread::spawn(move || {
+            let runtime = runtime::Builder::new_current_thread()
+                .enable_all()
+                .build()
+                .unwrap();
+            runtime.block_on(async move {
+                let transport = TcpTransport::bind(addr, HashMap::new()).await.unwrap();
+                let (mut incoming, _outgoing) = transport.split();
+                listening.send(()).unwrap();
+
+                tokio::pin!(stopped);
+                loop {
+                    tokio::select! {
+                        _ = &mut stopped => break,
+                        Some(message) = incoming.next() => {
+                            let _ = forward.send(message);
+                        }
+                    }
+                }
+            });
+        });
+        is_listening.recv().unwrap();
+
+        Self {
+            received,
+            stop,
+            thread,
+        }
+    }
+
+    async fn recv(&mut self) -> Message {
+        timeout(Duration::from_secs(5), self.received.recv())
+            .await
+            .expect("peer received nothing")
+            .unwrap()
+    }
+
+    /// Everything received until nothing more arrives for a second.
+    async fn recv_all(&mut self) -> Vec<Message> {
+        let mut messages = vec![self.recv().await];
+        while let Ok(Some(message)) = timeout(Duration::from_secs(1), self.received.recv()).await {
+            messages.push(message);
+        }
+        messages
+    }
+
+    fn stop(self) {
+        let _ = self.stop.send(());
+        self.thread.join().unwrap();
+    }
+}
+
+fn local_addr() -> SocketAddr {
+    std::net::TcpListener::bind("127.0.0.1:0")
+        .unwrap()
+        .local_addr()
+        .unwrap()
+}
+
+fn message(n: u64) -> Message {
+    Message {
+        src: NodeId::from("n1"),
+        dest: NodeId::from("n2"),
+        body: json!({ "type": "gossip", "n": n }),
+    }
+}
+
+#[tokio::test]
+async fn peers_are_redialled_after_they_restart() {
+    let addr = local_addr();
+
+    let mut peer = Peer::start(addr);
+    let node = TcpTransport::bind(
+        "127.0.0.1:0".parse().unwrap(),
+        HashMap::from([(NodeId::from("n2"), addr)]),
+    )
+    .await
+    .unwrap();
+    let (_incoming, mut outgoing) = node.split();
+
+    outgoing.send(message(1)).await.unwrap();
+    assert_eq!(peer.recv().await.body["n"], 1);
+
+    peer.stop();
+    let mut peer = Peer::start(addr);
+    // Give the dialler a chance to see the old connection close.
+    sleep(Duration::from_millis(100)).await;
+
+    outgoing.send(message(2)).await.unwrap();
+    assert_eq!(peer.recv().await.body["n"], 2);
+}
+
+#[tokio::test]
+async fn messages_for_a_peer_that_is_down_are_bounded() {
+    let addr = local_addr();
+    let node = TcpTransport::bind(
+        "127.0.0.1:0".parse().unwrap(),
+        HashMap::from([(NodeId::from("n2"), addr)]),
+    )
+    .await
+    .unwrap();
+    let (_incoming, mut outgoing) = node.split();
+
+    for n in 0..5000 {
+        outgoing.send(message(n)).await.unwrap();
+    }
+    let mut peer = Peer::start(addr);
+
+    let received = peer.recv_all().await;
+    assert!(received.len() < 5000, "{} messages queued", received.len());
+    assert_eq!(received[0].body["n"], 0);
+}